@@ -10,7 +10,7 @@ use config::{
 use eyre::{bail, eyre, Context, Error, Result};
 use fs_err::{create_dir_all, File};
 use humantime::parse_duration;
-use regex::RegexSet;
+use regex::{Regex, RegexSet};
 use semver::Version;
 use serde::{Deserialize, Serialize};
 use serde_with::DeserializeFromStr;
@@ -356,6 +356,86 @@ pub struct Daemon {
 
     /// The port that should be used for TCP on non unix systems
     pub tcp_port: u64,
+
+    /// The permissions set on the unix socket after it is created
+    pub socket_mode: u32,
+
+    /// The maximum number of started but unfinished commands to keep. Past this, the oldest is
+    /// dropped without being recorded
+    pub max_running: usize,
+
+    /// The longest sync may be paused for with `atuin daemon pause`, in seconds
+    pub max_pause_seconds: u64,
+
+    /// How often to check the history DB and record store agree, in seconds
+    pub consistency_frequency: u64,
+
+    /// The most differences a consistency check repairs by itself. Past this, it only reports them
+    pub consistency_max_repair: usize,
+
+    /// Write the timings of the daemon's tracing spans to this file, one JSON object per line
+    #[serde(default)]
+    pub span_file: Option<String>,
+
+    /// Rules for running a command when a matching command finishes
+    #[serde(default)]
+    pub watch: Vec<Watch>,
+
+    /// Which optional parts of the daemon to run
+    #[serde(default)]
+    pub components: DaemonComponents,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DaemonComponents {
+    /// Sync in the background, every `sync_frequency` seconds
+    pub sync: bool,
+
+    /// Check the history DB and record store agree, every `consistency_frequency` seconds
+    pub consistency: bool,
+}
+
+impl Default for DaemonComponents {
+    fn default() -> Self {
+        Self {
+            sync: true,
+            consistency: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Default, PartialEq, Eq)]
+pub enum WatchExit {
+    #[default]
+    #[serde(rename = "any")]
+    Any,
+
+    #[serde(rename = "success")]
+    Success,
+
+    #[serde(rename = "failure")]
+    Failure,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Watch {
+    /// A name for the rule, passed to the command as ATUIN_WATCH_NAME
+    pub name: String,
+
+    /// Only match commands matching this regex. Matches all commands if unset
+    #[serde(with = "serde_regex", default)]
+    pub command: Option<Regex>,
+
+    /// Only match commands that ran for at least this long
+    #[serde(default)]
+    pub min_duration_seconds: u64,
+
+    /// Only match commands that exited like this
+    #[serde(default)]
+    pub exit: WatchExit,
+
+    /// The command to run, via the system shell
+    pub exec: String,
 }
 
 impl Default for Preview {
@@ -374,6 +454,14 @@ impl Default for Daemon {
             socket_path: "".to_string(),
             systemd_socket: false,
             tcp_port: 8889,
+            socket_mode: 0o600,
+            max_running: 1000,
+            max_pause_seconds: 3600,
+            consistency_frequency: 3600,
+            consistency_max_repair: 1000,
+            span_file: None,
+            watch: vec![],
+            components: DaemonComponents::default(),
         }
     }
 }
@@ -472,7 +560,7 @@ impl Settings {
             .expect("Could not deserialize config")
     }
 
-    fn save_to_data_dir(filename: &str, value: &str) -> Result<()> {
+    pub fn save_to_data_dir(filename: &str, value: &str) -> Result<()> {
         let data_dir = atuin_common::utils::data_dir();
         let data_dir = data_dir.as_path();
 
@@ -483,7 +571,7 @@ impl Settings {
         Ok(())
     }
 
-    fn read_from_data_dir(filename: &str) -> Option<String> {
+    pub fn read_from_data_dir(filename: &str) -> Option<String> {
         let data_dir = atuin_common::utils::data_dir();
         let data_dir = data_dir.as_path();
 
@@ -727,6 +815,13 @@ impl Settings {
             .set_default("daemon.socket_path", socket_path.to_str())?
             .set_default("daemon.systemd_socket", false)?
             .set_default("daemon.tcp_port", 8889)?
+            .set_default("daemon.socket_mode", 0o600)?
+            .set_default("daemon.max_running", 1000)?
+            .set_default("daemon.max_pause_seconds", 3600)?
+            .set_default("daemon.components.sync", true)?
+            .set_default("daemon.components.consistency", true)?
+            .set_default("daemon.consistency_frequency", 3600)?
+            .set_default("daemon.consistency_max_repair", 1000)?
             .set_default(
                 "prefers_reduced_motion",
                 std::env::var("NO_MOTION")
@@ -793,6 +888,11 @@ impl Settings {
         let session_path = shellexpand::full(&session_path)?;
         settings.session_path = session_path.to_string();
 
+        if let Some(span_file) = settings.daemon.span_file {
+            let span_file = shellexpand::full(&span_file)?;
+            settings.daemon.span_file = Some(span_file.to_string());
+        }
+
         Ok(settings)
     }
 
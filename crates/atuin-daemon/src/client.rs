@@ -2,6 +2,7 @@ use eyre::{eyre, Result};
 #[cfg(windows)]
 use tokio::net::TcpStream;
 use tonic::transport::{Channel, Endpoint, Uri};
+use tonic::{Code, Status};
 use tower::service_fn;
 
 #[cfg(unix)]
@@ -10,9 +11,42 @@ use tokio::net::UnixStream;
 use atuin_client::history::History;
 
 use crate::history::{
-    history_client::HistoryClient as HistoryServiceClient, EndHistoryRequest, StartHistoryRequest,
+    history_client::HistoryClient as HistoryServiceClient, EndHistoryRequest, PauseRequest,
+    PrivateModeRequest, RestartRequest, ResumeRequest, RunningHistory, RunningRequest,
+    ShutdownRequest, StartHistoryRequest, StatusReply, StatusRequest, VerifyStoresReply,
+    VerifyStoresRequest,
 };
 
+/// The running daemon was started by an older atuin, and doesn't have this RPC
+#[derive(Debug)]
+pub struct Unsupported {
+    pub method: &'static str,
+}
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the running daemon does not support {}, as it is older than this version of atuin. Restart it after upgrading",
+            self.method
+        )
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+/// Newer RPCs are missing from daemons started by an older atuin, which fail them with
+/// Unimplemented. Say so, rather than surfacing a bare grpc error.
+fn unsupported(method: &'static str) -> impl FnOnce(Status) -> eyre::Report {
+    move |status| {
+        if status.code() == Code::Unimplemented {
+            Unsupported { method }.into()
+        } else {
+            eyre!("daemon {method} request failed: {}", status.message())
+        }
+    }
+}
+
 pub struct HistoryClient {
     client: HistoryServiceClient<Channel>,
 }
@@ -52,6 +86,8 @@ impl HistoryClient {
 
     pub async fn start_history(&mut self, h: History) -> Result<String> {
         let req = StartHistoryRequest {
+            // The id is unique to this command, so retrying with the same history is safe
+            idempotency_key: Some(h.id.0.clone()),
             command: h.command,
             cwd: h.cwd,
             hostname: h.hostname,
@@ -77,4 +113,117 @@ impl HistoryClient {
 
         Ok((resp.id, resp.idx))
     }
+
+    /// List the commands that have been started but not yet ended, oldest first
+    pub async fn running(&mut self, include_session: bool) -> Result<Vec<RunningHistory>> {
+        let req = RunningRequest { include_session };
+
+        let resp = self
+            .client
+            .running(req)
+            .await
+            .map_err(unsupported("running"))?;
+
+        Ok(resp.into_inner().history)
+    }
+
+    pub async fn status(&mut self) -> Result<StatusReply> {
+        let resp = self
+            .client
+            .status(StatusRequest {})
+            .await
+            .map_err(unsupported("status"))?;
+
+        Ok(resp.into_inner())
+    }
+
+    /// Pause background sync for this many seconds, or the daemon's maximum if zero. Returns
+    /// the unix timestamp the pause ends at.
+    pub async fn pause(&mut self, seconds: u64) -> Result<u64> {
+        let resp = self
+            .client
+            .pause(PauseRequest { seconds })
+            .await
+            .map_err(unsupported("pause"))?;
+
+        Ok(resp.into_inner().sync_paused_until)
+    }
+
+    pub async fn resume(&mut self) -> Result<()> {
+        self.client
+            .resume(ResumeRequest {})
+            .await
+            .map_err(unsupported("resume"))?;
+
+        Ok(())
+    }
+
+    /// Ask the daemon to shut down. It finishes any requests in flight first. With `handoff`,
+    /// it saves the running commands for the next daemon to pick up.
+    pub async fn shutdown(&mut self, handoff: bool) -> Result<()> {
+        self.client
+            .shutdown(ShutdownRequest { handoff })
+            .await
+            .map_err(unsupported("shutdown"))?;
+
+        Ok(())
+    }
+
+    /// Compare the history DB and record store, repairing what's missing from either if `repair`
+    pub async fn verify_stores(&mut self, repair: bool) -> Result<VerifyStoresReply> {
+        let resp = self
+            .client
+            .verify_stores(VerifyStoresRequest { repair })
+            .await
+            .map_err(unsupported("verify_stores"))?;
+
+        Ok(resp.into_inner())
+    }
+
+    /// Ask the daemon to exec `binary` (or itself) in place, keeping its pid, listening socket
+    /// and running commands. This connection is closed once requests in flight have finished.
+    pub async fn restart(&mut self, binary: Option<String>) -> Result<()> {
+        self.client
+            .restart(RestartRequest { binary })
+            .await
+            .map_err(unsupported("restart"))?;
+
+        Ok(())
+    }
+
+    /// Turn private mode on or off, or leave it as-is if `enabled` is None. Returns the
+    /// resulting state.
+    pub async fn private_mode(&mut self, enabled: Option<bool>) -> Result<bool> {
+        let req = PrivateModeRequest { enabled };
+
+        let resp = self
+            .client
+            .private_mode(req)
+            .await
+            .map_err(unsupported("private_mode"))?;
+
+        Ok(resp.into_inner().enabled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Status;
+
+    use super::unsupported;
+
+    #[test]
+    fn unimplemented_means_old_daemon() {
+        let err = unsupported("pause")(Status::unimplemented("Unimplemented"));
+
+        assert!(err.to_string().contains("does not support pause"));
+        assert!(err.to_string().contains("Restart it after upgrading"));
+    }
+
+    #[test]
+    fn other_errors_name_the_method() {
+        let err = unsupported("status")(Status::internal("boom"));
+
+        assert_eq!(err.to_string(), "daemon status request failed: boom");
+    }
 }
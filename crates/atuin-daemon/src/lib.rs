@@ -1,3 +1,9 @@
+/// The daemon's protocol version, reported by the Status RPC. Clients compare it with their own to
+/// notice a daemon left running from an older atuin.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 pub mod client;
 pub mod history;
 pub mod server;
+pub mod spans;
+pub mod warm;
@@ -1,10 +1,10 @@
 use eyre::WrapErr;
 
 use atuin_client::encryption;
-use atuin_client::history::store::HistoryStore;
 use atuin_client::record::sqlite_store::SqliteStore;
 use atuin_client::settings::Settings;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use time::OffsetDateTime;
 use tracing::{instrument, Level};
@@ -13,33 +13,118 @@ use atuin_client::database::{Database, Sqlite as HistoryDatabase};
 use atuin_client::history::{History, HistoryId};
 use dashmap::DashMap;
 use eyre::Result;
+use tokio::sync::Notify;
 use tonic::{transport::Server, Request, Response, Status};
 
 use crate::history::history_server::{History as HistorySvc, HistoryServer};
 
-use crate::history::{EndHistoryReply, EndHistoryRequest, StartHistoryReply, StartHistoryRequest};
-
+use crate::history::{
+    EndHistoryReply, EndHistoryRequest, PauseReply, PauseRequest, PrivateModeReply,
+    PrivateModeRequest, RestartReply, RestartRequest, ResumeReply, ResumeRequest, RunningHistory,
+    RunningReply, RunningRequest, ShutdownReply, ShutdownRequest, StartHistoryReply,
+    StartHistoryRequest, StatusReply, StatusRequest, VerifyStoresReply, VerifyStoresRequest,
+};
+
+mod consistency;
+mod db;
+mod handoff;
+mod idempotency;
+mod pause;
+mod restart;
 mod sync;
+mod watch;
 
 #[derive(Debug)]
 pub struct HistoryService {
     // A store for WIP history
     // This is history that has not yet been completed, aka a command that's current running.
     running: Arc<DashMap<HistoryId, History>>,
-    store: HistoryStore,
-    history_db: HistoryDatabase,
+    // Commands started in private mode, with when they started. Only their ids are kept, so
+    // nothing about them can be listed, saved, or handed to the next daemon.
+    private_running: Arc<DashMap<HistoryId, OffsetDateTime>>,
+    // Ids handed out for recent idempotency keys, so retried starts don't run twice
+    recent_starts: idempotency::RecentStarts,
+    // A misbehaving client can start commands without ever ending them, so bound the above
+    max_running: usize,
+    // While set, finished commands are not saved, pushed to the store, or synced
+    private: Arc<AtomicBool>,
+    // Notified when a client asks the daemon to shut down or restart
+    shutdown: Arc<Notify>,
+    // What to do once the server has stopped
+    exit: restart::ExitPlan,
+    // Changes when the daemon restarts in place, unlike the pid
+    started_at: OffsetDateTime,
+    pause: pause::Pause,
+    max_pause: time::Duration,
+    // Whether the sync worker runs at all. If not, there is nothing to pause.
+    sync_enabled: bool,
+    dbs: db::Databases,
+    consistency: consistency::Consistency,
+    watcher: watch::Watcher,
 }
 
 impl HistoryService {
-    pub fn new(store: HistoryStore, history_db: HistoryDatabase) -> Self {
+    pub fn new(
+        dbs: db::Databases,
+        consistency: consistency::Consistency,
+        watcher: watch::Watcher,
+        pause: pause::Pause,
+        settings: &Settings,
+    ) -> Self {
         Self {
             running: Arc::new(DashMap::new()),
-            store,
-            history_db,
+            private_running: Arc::new(DashMap::new()),
+            recent_starts: idempotency::RecentStarts::default(),
+            max_running: settings.daemon.max_running,
+            private: Arc::new(AtomicBool::new(false)),
+            shutdown: Arc::new(Notify::new()),
+            exit: restart::ExitPlan::default(),
+            started_at: OffsetDateTime::now_utc(),
+            pause,
+            max_pause: time::Duration::seconds(
+                i64::try_from(settings.daemon.max_pause_seconds).unwrap_or(i64::MAX),
+            ),
+            sync_enabled: settings.daemon.components.sync,
+            dbs,
+            consistency,
+            watcher,
         }
     }
 }
 
+/// Drop the longest-running commands until at most `max` remain, never dropping `keep`.
+/// Evicting the oldest means legitimately long-running commands are the ones most at risk, but
+/// they'd have to outlive `max` newer commands that were never ended first.
+fn evict_running<V>(
+    running: &DashMap<HistoryId, V>,
+    max: usize,
+    keep: Option<&HistoryId>,
+    timestamp: impl Fn(&V) -> OffsetDateTime,
+) {
+    while running.len() > max {
+        let oldest = running
+            .iter()
+            .filter(|h| Some(h.key()) != keep)
+            .min_by_key(|h| timestamp(h.value()))
+            .map(|h| h.key().clone());
+
+        let Some(oldest) = oldest else {
+            break;
+        };
+
+        tracing::warn!(
+            id = oldest.to_string(),
+            max,
+            "too many running commands, dropping the oldest"
+        );
+        running.remove(&oldest);
+    }
+}
+
+fn sync_disabled() -> Status {
+    Status::failed_precondition("sync is disabled on this daemon (daemon.components.sync = false)")
+}
+
 #[tonic::async_trait()]
 impl HistorySvc for HistoryService {
     #[instrument(skip_all, level = Level::INFO)]
@@ -72,9 +157,26 @@ impl HistorySvc for HistoryService {
         // complete.
         // If anyone relied on the old behaviour, we could perhaps insert to the history db here
         // too. I'd rather keep it pure, unless that ends up being the case.
-        let id = h.id.clone();
-        tracing::info!(id = id.to_string(), "start history");
-        running.insert(id.clone(), h);
+        let start = || {
+            let id = h.id.clone();
+
+            if self.private.load(Ordering::Relaxed) {
+                tracing::info!(id = id.to_string(), "start history in private mode");
+                self.private_running.insert(id.clone(), h.timestamp);
+                evict_running(&self.private_running, self.max_running, Some(&id), |t| *t);
+            } else {
+                tracing::info!(id = id.to_string(), "start history");
+                running.insert(id.clone(), h);
+                evict_running(&running, self.max_running, Some(&id), |h| h.timestamp);
+            }
+
+            id
+        };
+
+        let id = match req.idempotency_key {
+            Some(key) => self.recent_starts.get_or_start(key, start),
+            None => start(),
+        };
 
         let reply = StartHistoryReply { id: id.to_string() };
 
@@ -91,7 +193,34 @@ impl HistorySvc for HistoryService {
 
         let id = HistoryId(req.id);
 
+        if self.private_running.remove(&id).is_some() {
+            tracing::info!(
+                id = id.0.to_string(),
+                "started in private mode, not storing history"
+            );
+
+            let reply = EndHistoryReply {
+                id: id.0.to_string(),
+                idx: 0,
+                skipped: true,
+            };
+
+            return Ok(Response::new(reply));
+        }
+
         if let Some((_, mut history)) = running.remove(&id) {
+            if self.private.load(Ordering::Relaxed) {
+                tracing::info!(id = id.0.to_string(), "private mode, not storing history");
+
+                let reply = EndHistoryReply {
+                    id: id.0.to_string(),
+                    idx: 0,
+                    skipped: true,
+                };
+
+                return Ok(Response::new(reply));
+            }
+
             history.exit = req.exit;
             history.duration = match req.duration {
                 0 => i64::try_from(
@@ -101,11 +230,16 @@ impl HistorySvc for HistoryService {
                 value => i64::try_from(value).expect("failed to get i64 duration"),
             };
 
+            // Until it is in both, a consistency check would see it missing from the store
+            let _writing = self.dbs.writing().await;
+            self.dbs.check().await;
+
             // Perhaps allow the incremental build to handle this entirely.
-            self.history_db
-                .save(&history)
-                .await
-                .map_err(|e| Status::internal(format!("failed to write to db: {e:?}")))?;
+            let saved = match self.dbs.history_db().save(&history).await {
+                Err(e) if self.dbs.recover(&e).await => self.dbs.history_db().save(&history).await,
+                saved => saved,
+            };
+            saved.map_err(|e| Status::internal(format!("failed to write to db: {e:?}")))?;
 
             tracing::info!(
                 id = id.0.to_string(),
@@ -113,14 +247,22 @@ impl HistorySvc for HistoryService {
                 "end history"
             );
 
-            let (id, idx) =
-                self.store.push(history).await.map_err(|e| {
-                    Status::internal(format!("failed to push record to store: {e:?}"))
-                })?;
+            let pushed = match self.dbs.history_store().push(history.clone()).await {
+                Err(e) if self.dbs.recover(e.as_ref()).await => {
+                    self.dbs.history_store().push(history.clone()).await
+                }
+                pushed => pushed,
+            };
+            let (id, idx) = pushed
+                .map_err(|e| Status::internal(format!("failed to push record to store: {e:?}")))?;
+
+            // Only once the command is fully persisted
+            self.watcher.trigger(&history);
 
             let reply = EndHistoryReply {
                 id: id.0.to_string(),
                 idx,
+                skipped: false,
             };
 
             return Ok(Response::new(reply));
@@ -130,10 +272,227 @@ impl HistorySvc for HistoryService {
             "could not find history with id: {id}"
         )))
     }
+
+    #[instrument(skip_all, level = Level::INFO)]
+    async fn running(
+        &self,
+        request: Request<RunningRequest>,
+    ) -> Result<Response<RunningReply>, Status> {
+        let req = request.into_inner();
+        let now = OffsetDateTime::now_utc();
+
+        let mut history: Vec<RunningHistory> = self
+            .running
+            .iter()
+            .map(|h| RunningHistory {
+                id: h.id.to_string(),
+                timestamp: h.timestamp.unix_timestamp_nanos() as u64,
+                elapsed: u64::try_from((now - h.timestamp).whole_nanoseconds()).unwrap_or(0),
+                command: h.command.clone(),
+                cwd: h.cwd.clone(),
+                session: if req.include_session {
+                    h.session.clone()
+                } else {
+                    String::new()
+                },
+                hostname: h.hostname.clone(),
+            })
+            .collect();
+
+        history.sort_by_key(|h| h.timestamp);
+
+        Ok(Response::new(RunningReply { history }))
+    }
+
+    #[instrument(skip_all, level = Level::INFO)]
+    async fn status(
+        &self,
+        _request: Request<StatusRequest>,
+    ) -> Result<Response<StatusReply>, Status> {
+        let sync_paused_until = self
+            .pause
+            .paused_until(OffsetDateTime::now_utc())
+            .map_or(0, |until| until.unix_timestamp() as u64);
+
+        let reply = StatusReply {
+            pid: std::process::id(),
+            sync_paused_until,
+            sync_disabled: !self.sync_enabled,
+            version: crate::VERSION.to_string(),
+            started_at: u64::try_from(self.started_at.unix_timestamp_nanos()).unwrap_or(0),
+            db_reconnects: self.dbs.reconnects(),
+            db_degraded: self.dbs.degraded(),
+            stores_degraded: self.consistency.degraded(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    #[instrument(skip_all, level = Level::INFO)]
+    async fn shutdown(
+        &self,
+        request: Request<ShutdownRequest>,
+    ) -> Result<Response<ShutdownReply>, Status> {
+        let req = request.into_inner();
+        tracing::info!(handoff = req.handoff, "shutdown requested");
+
+        if req.handoff {
+            self.exit.set(restart::Exit::Handoff);
+        }
+
+        // The server shuts down gracefully, so this reply is still sent
+        self.shutdown.notify_one();
+
+        Ok(Response::new(ShutdownReply {}))
+    }
+
+    #[instrument(skip_all, level = Level::INFO)]
+    async fn verify_stores(
+        &self,
+        request: Request<VerifyStoresRequest>,
+    ) -> Result<Response<VerifyStoresReply>, Status> {
+        let req = request.into_inner();
+
+        let report = self
+            .consistency
+            .verify(&self.dbs, req.repair)
+            .await
+            .map_err(|e| Status::internal(format!("failed to verify stores: {e:?}")))?;
+
+        let reply = VerifyStoresReply {
+            history_rows: report.history_rows as u64,
+            store_records: report.store_records as u64,
+            missing_from_store: report.missing_from_store as u64,
+            missing_from_history: report.missing_from_history as u64,
+            repaired: report.repaired,
+            degraded: report.degraded,
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    #[cfg(unix)]
+    #[instrument(skip_all, level = Level::INFO)]
+    async fn restart(
+        &self,
+        request: Request<RestartRequest>,
+    ) -> Result<Response<RestartReply>, Status> {
+        use tonic::transport::server::UdsConnectInfo;
+
+        // Whoever picks the binary picks what runs as us, so only let the daemon's own user do it
+        let peer = request
+            .extensions()
+            .get::<UdsConnectInfo>()
+            .and_then(|info| info.peer_cred)
+            .map(|cred| cred.uid());
+        let req = request.into_inner();
+
+        let binary = match req.binary {
+            Some(binary) if peer == Some(rustix::process::getuid().as_raw()) => {
+                PathBuf::from(binary)
+            }
+            Some(_) => {
+                return Err(Status::permission_denied(
+                    "only the daemon's owner can choose the binary to restart with",
+                ))
+            }
+            None => std::env::current_exe()
+                .map_err(|e| Status::internal(format!("failed to find the daemon binary: {e}")))?,
+        };
+
+        if !binary.is_absolute() {
+            return Err(Status::invalid_argument("binary must be an absolute path"));
+        }
+
+        // Checked here, so a typo fails this request rather than the exec after shutting down
+        if !binary.is_file() {
+            return Err(Status::invalid_argument(format!(
+                "binary {binary:?} is not a file"
+            )));
+        }
+
+        tracing::info!(?binary, "restart requested");
+        self.exit.set(restart::Exit::Exec(binary));
+        self.shutdown.notify_one();
+
+        Ok(Response::new(RestartReply {}))
+    }
+
+    #[cfg(not(unix))]
+    async fn restart(
+        &self,
+        _request: Request<RestartRequest>,
+    ) -> Result<Response<RestartReply>, Status> {
+        Err(Status::unimplemented(
+            "restarting in place is only supported on unix",
+        ))
+    }
+
+    #[instrument(skip_all, level = Level::INFO)]
+    async fn pause(&self, request: Request<PauseRequest>) -> Result<Response<PauseReply>, Status> {
+        let req = request.into_inner();
+
+        if !self.sync_enabled {
+            return Err(sync_disabled());
+        }
+
+        let requested = time::Duration::seconds(i64::try_from(req.seconds).unwrap_or(i64::MAX));
+        let duration = if req.seconds == 0 {
+            self.max_pause
+        } else {
+            requested.min(self.max_pause)
+        };
+
+        let until = OffsetDateTime::now_utc()
+            .checked_add(duration)
+            .ok_or_else(|| Status::invalid_argument("pause duration is too long"))?;
+        tracing::info!(until = until.to_string(), "pausing sync");
+        self.pause.until(until);
+
+        let reply = PauseReply {
+            sync_paused_until: until.unix_timestamp() as u64,
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    #[instrument(skip_all, level = Level::INFO)]
+    async fn resume(
+        &self,
+        _request: Request<ResumeRequest>,
+    ) -> Result<Response<ResumeReply>, Status> {
+        if !self.sync_enabled {
+            return Err(sync_disabled());
+        }
+
+        tracing::info!("resuming sync");
+        self.pause.resume();
+
+        Ok(Response::new(ResumeReply {}))
+    }
+
+    #[instrument(skip_all, level = Level::INFO)]
+    async fn private_mode(
+        &self,
+        request: Request<PrivateModeRequest>,
+    ) -> Result<Response<PrivateModeReply>, Status> {
+        let req = request.into_inner();
+
+        if let Some(enabled) = req.enabled {
+            tracing::info!(enabled, "set private mode");
+            self.private.store(enabled, Ordering::Relaxed);
+        }
+
+        let reply = PrivateModeReply {
+            enabled: self.private.load(Ordering::Relaxed),
+        };
+
+        Ok(Response::new(reply))
+    }
 }
 
 #[cfg(unix)]
-async fn shutdown_signal(socket: Option<PathBuf>) {
+async fn shutdown_signal(socket: Option<PathBuf>, requested: Arc<Notify>, exit: restart::ExitPlan) {
     let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
         .expect("failed to register sigterm handler");
     let mut int = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())
@@ -142,96 +501,171 @@ async fn shutdown_signal(socket: Option<PathBuf>) {
     tokio::select! {
         _  = term.recv() => {},
         _  = int.recv() => {},
+        _  = requested.notified() => {},
     }
 
-    eprintln!("Removing socket...");
-    if let Some(socket) = socket {
-        std::fs::remove_file(socket).expect("failed to remove socket");
+    // When restarting in place, the next binary carries on listening on this socket
+    if !matches!(exit.get(), restart::Exit::Exec(_)) {
+        eprintln!("Removing socket...");
+        if let Some(socket) = socket {
+            std::fs::remove_file(socket).expect("failed to remove socket");
+        }
     }
     eprintln!("Shutting down...");
 }
 
 #[cfg(windows)]
-async fn shutdown_signal() {
-    tokio::signal::windows::ctrl_c()
-        .expect("failed to register signal handler")
-        .recv()
-        .await;
+async fn shutdown_signal(requested: Arc<Notify>) {
+    let mut ctrl_c = tokio::signal::windows::ctrl_c().expect("failed to register signal handler");
+
+    tokio::select! {
+        _  = ctrl_c.recv() => {},
+        _  = requested.notified() => {},
+    }
+
     eprintln!("Shutting down...");
 }
 
+/// The socket the daemon serves on, bound before anything else starts
 #[cfg(unix)]
-async fn start_server(settings: Settings, history: HistoryService) -> Result<()> {
+struct Listener {
+    uds: tokio::net::UnixListener,
+    // The socket file to remove on shutdown, if we created it
+    cleanup: Option<PathBuf>,
+}
+
+#[cfg(not(unix))]
+struct Listener {
+    tcp: tokio::net::TcpListener,
+}
+
+/// Take the listening socket passed to us with the `LISTEN_FDS` protocol
+#[cfg(unix)]
+fn take_listen_fd() -> Result<std::os::unix::net::UnixListener> {
+    use eyre::OptionExt;
+
+    let listener = listenfd::ListenFd::from_env()
+        .take_unix_listener(0)?
+        .ok_or_eyre("missing socket")?;
+    listener.set_nonblocking(true)?;
+
+    Ok(listener)
+}
+
+#[cfg(unix)]
+async fn bind(settings: &Settings) -> Result<Listener> {
+    use eyre::OptionExt;
     use tokio::net::UnixListener;
-    use tokio_stream::wrappers::UnixListenerStream;
 
-    let socket_path = settings.daemon.socket_path;
-
-    let (uds, cleanup) = if cfg!(target_os = "linux") && settings.daemon.systemd_socket {
-        #[cfg(target_os = "linux")]
-        {
-            use eyre::OptionExt;
-            tracing::info!("getting systemd socket");
-            let listener = listenfd::ListenFd::from_env()
-                .take_unix_listener(0)?
-                .ok_or_eyre("missing systemd socket")?;
-            listener.set_nonblocking(true)?;
-            let actual_path = listener
-                .local_addr()
-                .context("getting systemd socket's path")
-                .and_then(|addr| {
-                    addr.as_pathname()
-                        .ok_or_eyre("systemd socket missing path")
-                        .map(|path| path.to_owned())
-                });
-            match actual_path {
-                Ok(actual_path) => {
-                    tracing::info!("listening on systemd socket: {actual_path:?}");
-                    if actual_path != std::path::Path::new(&socket_path) {
-                        tracing::warn!(
-                            "systemd socket is not at configured client path: {socket_path:?}"
-                        );
-                    }
-                }
-                Err(err) => {
-                    tracing::warn!("could not detect systemd socket path, ensure that it's at the configured path: {socket_path:?}, error: {err:?}");
+    let socket_path = settings.daemon.socket_path.clone();
+
+    // Set by the daemon we replaced when it restarted in place. Not passed on to anything we run.
+    let handoff = std::env::var_os(restart::HANDOFF_ENV).is_some();
+    std::env::remove_var(restart::HANDOFF_ENV);
+
+    if handoff {
+        tracing::info!("taking over the socket from the previous daemon");
+        let listener = take_listen_fd().context("getting the previous daemon's socket")?;
+
+        Ok(Listener {
+            uds: UnixListener::from_std(listener)?,
+            cleanup: (!settings.daemon.systemd_socket).then(|| socket_path.into()),
+        })
+    } else if cfg!(target_os = "linux") && settings.daemon.systemd_socket {
+        tracing::info!("getting systemd socket");
+        let listener = take_listen_fd().context("getting systemd socket")?;
+        let actual_path = listener
+            .local_addr()
+            .context("getting systemd socket's path")
+            .and_then(|addr| {
+                addr.as_pathname()
+                    .ok_or_eyre("systemd socket missing path")
+                    .map(|path| path.to_owned())
+            });
+        match actual_path {
+            Ok(actual_path) => {
+                tracing::info!("listening on systemd socket: {actual_path:?}");
+                if actual_path != std::path::Path::new(&socket_path) {
+                    tracing::warn!(
+                        "systemd socket is not at configured client path: {socket_path:?}"
+                    );
                 }
             }
-            (UnixListener::from_std(listener)?, false)
+            Err(err) => {
+                tracing::warn!("could not detect systemd socket path, ensure that it's at the configured path: {socket_path:?}, error: {err:?}");
+            }
         }
-        #[cfg(not(target_os = "linux"))]
-        unreachable!()
+        Ok(Listener {
+            uds: UnixListener::from_std(listener)?,
+            cleanup: None,
+        })
     } else {
+        use std::os::unix::fs::PermissionsExt;
+
+        use rustix::{fs::Mode, process::umask};
+
         tracing::info!("listening on unix socket {socket_path:?}");
-        (UnixListener::bind(socket_path.clone())?, true)
-    };
 
-    let uds_stream = UnixListenerStream::new(uds);
+        // Create the socket owner-only, so that nobody else can connect before the mode below is
+        // applied. Anyone who can connect can read and write history. The umask is process wide,
+        // so this must happen before anything else that might create files is started.
+        let previous = umask(Mode::RWXG | Mode::RWXO | Mode::XUSR);
+        let listener = UnixListener::bind(socket_path.clone());
+        umask(previous);
+        let listener = listener?;
+
+        let mode = settings.daemon.socket_mode;
+        std::fs::set_permissions(&socket_path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("failed to set permissions on socket {socket_path:?}"))?;
+        tracing::info!("set socket permissions to {mode:o}");
+
+        Ok(Listener {
+            uds: listener,
+            cleanup: Some(socket_path.into()),
+        })
+    }
+}
+
+#[cfg(not(unix))]
+async fn bind(settings: &Settings) -> Result<Listener> {
+    use tokio::net::TcpListener;
+
+    let port = settings.daemon.tcp_port;
+    let url = format!("127.0.0.1:{}", port);
+    let tcp = TcpListener::bind(url).await?;
+
+    tracing::info!("listening on tcp port {:?}", port);
+
+    Ok(Listener { tcp })
+}
+
+#[cfg(unix)]
+async fn start_server(listener: Listener, history: HistoryService) -> Result<()> {
+    use tokio_stream::wrappers::UnixListenerStream;
+
+    let uds_stream = UnixListenerStream::new(listener.uds);
+    let requested = history.shutdown.clone();
+    let exit = history.exit.clone();
     Server::builder()
         .add_service(HistoryServer::new(history))
         .serve_with_incoming_shutdown(
             uds_stream,
-            shutdown_signal(cleanup.then_some(socket_path.into())),
+            shutdown_signal(listener.cleanup, requested, exit),
         )
         .await?;
     Ok(())
 }
 
 #[cfg(not(unix))]
-async fn start_server(settings: Settings, history: HistoryService) -> Result<()> {
-    use tokio::net::TcpListener;
+async fn start_server(listener: Listener, history: HistoryService) -> Result<()> {
     use tokio_stream::wrappers::TcpListenerStream;
 
-    let port = settings.daemon.tcp_port;
-    let url = format!("127.0.0.1:{}", port);
-    let tcp = TcpListener::bind(url).await?;
-    let tcp_stream = TcpListenerStream::new(tcp);
-
-    tracing::info!("listening on tcp port {:?}", port);
+    let tcp_stream = TcpListenerStream::new(listener.tcp);
 
+    let requested = history.shutdown.clone();
     Server::builder()
         .add_service(HistoryServer::new(history))
-        .serve_with_incoming_shutdown(tcp_stream, shutdown_signal())
+        .serve_with_incoming_shutdown(tcp_stream, shutdown_signal(requested))
         .await?;
     Ok(())
 }
@@ -245,22 +679,162 @@ pub async fn listen(
     store: SqliteStore,
     history_db: HistoryDatabase,
 ) -> Result<()> {
+    // Bind first, see bind() for why
+    let listener = bind(&settings).await?;
+
     let encryption_key: [u8; 32] = encryption::load_key(&settings)
         .context("could not load encryption key")?
         .into();
 
     let host_id = Settings::host_id().expect("failed to get host_id");
-    let history_store = HistoryStore::new(store.clone(), host_id, encryption_key);
+    let dbs = db::Databases::new(history_db, store, &settings, host_id, encryption_key);
+
+    let watcher = watch::Watcher::new(settings.daemon.watch.clone());
+    let pause = pause::Pause::default();
+    let consistency = consistency::Consistency::new(&settings);
+    let history = HistoryService::new(
+        dbs.clone(),
+        consistency.clone(),
+        watcher,
+        pause.clone(),
+        &settings,
+    );
+
+    let restored = handoff::load();
+    for h in restored.running {
+        tracing::info!(id = h.id.to_string(), "restored running command");
+        history.running.insert(h.id.clone(), h);
+    }
+    evict_running(&history.running, history.max_running, None, |h| h.timestamp);
+
+    if restored.private {
+        tracing::info!("private mode was on before the restart, keeping it on");
+        history.private.store(true, Ordering::Relaxed);
+    }
 
-    let history = HistoryService::new(history_store.clone(), history_db.clone());
+    let running = history.running.clone();
+    let private = history.private.clone();
+    let exit = history.exit.clone();
+
+    // The server closes its listener when it stops, so keep another handle to pass on if we are
+    // restarting in place
+    #[cfg(unix)]
+    let (inherit, cleanup) = {
+        use std::os::fd::AsFd;
+
+        let fd = listener.uds.as_fd().try_clone_to_owned()?;
+        (
+            std::os::unix::net::UnixListener::from(fd),
+            listener.cleanup.clone(),
+        )
+    };
 
     // start services
-    tokio::spawn(sync::worker(
-        settings.clone(),
-        store,
-        history_store,
-        history_db,
-    ));
-
-    start_server(settings, history).await
+    if settings.daemon.components.sync {
+        tokio::spawn(sync::worker(settings.clone(), dbs.clone(), pause));
+    } else {
+        tracing::info!("sync component disabled, not starting sync worker");
+    }
+
+    if settings.daemon.components.consistency {
+        tokio::spawn(consistency::worker(settings.clone(), dbs, consistency));
+    } else {
+        tracing::info!("consistency component disabled, not starting consistency worker");
+    }
+
+    start_server(listener, history).await?;
+
+    // Requests in flight have finished, so nothing else touches the running commands now
+    let private = private.load(Ordering::Relaxed);
+    match exit.get() {
+        restart::Exit::Stop => Ok(()),
+        restart::Exit::Handoff => handoff::save(&running, private),
+        #[cfg(unix)]
+        restart::Exit::Exec(binary) => {
+            let err = match handoff::save(&running, private) {
+                // Restarting would quietly leave private mode, better to stop recording entirely
+                Err(e) if private => e.wrap_err("not restarting, failed to hand over private mode"),
+                saved => {
+                    // Losing the running commands is better than not restarting at all
+                    if let Err(e) = saved {
+                        tracing::warn!("failed to save running commands: {e}");
+                    }
+
+                    restart::exec(&binary, &inherit)
+                }
+            };
+
+            // Nothing is listening any more, so don't leave the socket behind for clients to
+            // wait on
+            if let Some(socket) = cleanup {
+                let _ = std::fs::remove_file(socket);
+            }
+
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dashmap::DashMap;
+    use time::{Duration, OffsetDateTime};
+
+    use atuin_client::history::{History, HistoryId};
+
+    use super::evict_running;
+
+    fn running(count: i64) -> (DashMap<HistoryId, History>, Vec<HistoryId>) {
+        let now = OffsetDateTime::now_utc();
+        let running = DashMap::new();
+        let mut ids = vec![];
+
+        for i in 0..count {
+            let h: History = History::daemon()
+                .timestamp(now + Duration::seconds(i))
+                .command(format!("sleep {i}"))
+                .cwd("/")
+                .session("session")
+                .hostname("host:user")
+                .build()
+                .into();
+
+            ids.push(h.id.clone());
+            running.insert(h.id.clone(), h);
+        }
+
+        (running, ids)
+    }
+
+    #[test]
+    fn under_cap_is_untouched() {
+        let (running, ids) = running(3);
+        evict_running(&running, 3, Some(&ids[2]), |h| h.timestamp);
+
+        assert_eq!(running.len(), 3);
+    }
+
+    #[test]
+    fn evicts_oldest_past_cap() {
+        let (running, ids) = running(5);
+        evict_running(&running, 3, Some(&ids[4]), |h| h.timestamp);
+
+        assert_eq!(running.len(), 3);
+        assert!(!running.contains_key(&ids[0]));
+        assert!(!running.contains_key(&ids[1]));
+        assert!(running.contains_key(&ids[2]));
+        assert!(running.contains_key(&ids[4]));
+    }
+
+    #[test]
+    fn never_evicts_the_new_command() {
+        let (running, ids) = running(3);
+
+        // a client sending an old timestamp shouldn't have its own command evicted
+        evict_running(&running, 2, Some(&ids[0]), |h| h.timestamp);
+
+        assert_eq!(running.len(), 2);
+        assert!(running.contains_key(&ids[0]));
+        assert!(!running.contains_key(&ids[1]));
+    }
 }
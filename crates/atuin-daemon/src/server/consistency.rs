@@ -0,0 +1,270 @@
+//! Checks the history DB and the record store agree, repairing small differences.
+//!
+//! Usually after a crash, history rows can end up with no record in the store, so they never
+//! sync, or records can end up never built into the history DB, so they never show locally. Both
+//! are found by comparing history ids. A handful is repaired by writing what's missing. More than
+//! `daemon.consistency_max_repair` points at something bigger, so is only reported, along with
+//! the commands to repair it by hand.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use ::time::OffsetDateTime;
+use eyre::Result;
+use tokio::time::{self, MissedTickBehavior};
+
+use atuin_client::database::{Context, Database};
+use atuin_client::history::store::HistoryRecord;
+use atuin_client::history::{History, HistoryId};
+use atuin_client::settings::Settings;
+
+use super::db::Databases;
+
+/// What a check found, and what it did about it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub history_rows: usize,
+    pub store_records: usize,
+    pub missing_from_store: usize,
+    pub missing_from_history: usize,
+    pub repaired: bool,
+    pub degraded: bool,
+}
+
+/// The differences between the two, by history id
+#[derive(Debug, Default, PartialEq)]
+struct Drift {
+    /// Rows in the history DB with no record, so never synced
+    missing_from_store: Vec<History>,
+    /// Created in the store, and never deleted, but not in the history DB, so never shown
+    missing_from_history: Vec<History>,
+}
+
+impl Drift {
+    fn len(&self) -> usize {
+        self.missing_from_store.len() + self.missing_from_history.len()
+    }
+}
+
+fn compare(rows: Vec<History>, records: Vec<HistoryRecord>) -> Drift {
+    let mut created = HashMap::new();
+    let mut deleted = HashSet::new();
+
+    for record in records {
+        match record {
+            HistoryRecord::Create(h) => {
+                created.insert(h.id.clone(), h);
+            }
+            HistoryRecord::Delete(id) => {
+                deleted.insert(id);
+            }
+        }
+    }
+
+    let in_history: HashSet<HistoryId> = rows.iter().map(|h| h.id.clone()).collect();
+
+    let missing_from_store = rows
+        .into_iter()
+        .filter(|h| !created.contains_key(&h.id) && !deleted.contains(&h.id))
+        .collect();
+
+    let mut missing_from_history: Vec<History> = created
+        .into_values()
+        .filter(|h| !in_history.contains(&h.id) && !deleted.contains(&h.id))
+        .collect();
+    missing_from_history.sort_by_key(|h| h.timestamp);
+
+    Drift {
+        missing_from_store,
+        missing_from_history,
+    }
+}
+
+/// The result of the last check, for the daemon's status
+#[derive(Debug, Clone, Default)]
+pub struct Consistency {
+    last: Arc<Mutex<Option<Report>>>,
+    max_repair: usize,
+}
+
+impl Consistency {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            last: Arc::default(),
+            max_repair: settings.daemon.consistency_max_repair,
+        }
+    }
+
+    /// Whether the last check found more than it could repair
+    pub fn degraded(&self) -> bool {
+        self.last
+            .lock()
+            .expect("consistency report poisoned")
+            .as_ref()
+            .is_some_and(|r| r.degraded)
+    }
+
+    /// Compare the databases, and if `repair`, write whatever is missing from either
+    pub async fn verify(&self, dbs: &Databases, repair: bool) -> Result<Report> {
+        // Nothing can be half written while we look, or we'd "repair" it into a duplicate
+        let _exclusive = dbs.exclusive().await;
+
+        let history_db = dbs.history_db();
+        let history_store = dbs.history_store();
+
+        // No filters are used, so the context is never looked at
+        let context = Context {
+            session: String::new(),
+            cwd: String::new(),
+            hostname: String::new(),
+            host_id: String::new(),
+            git_root: None,
+        };
+        let rows = history_db.list(&[], &context, None, false, true).await?;
+        let records = history_store.history().await?;
+
+        let mut report = Report {
+            history_rows: rows.len(),
+            store_records: records.len(),
+            ..Report::default()
+        };
+
+        let drift = compare(rows, records);
+        report.missing_from_store = drift.missing_from_store.len();
+        report.missing_from_history = drift.missing_from_history.len();
+
+        if drift.len() > self.max_repair {
+            tracing::warn!(
+                missing_from_store = report.missing_from_store,
+                missing_from_history = report.missing_from_history,
+                "history db and record store disagree, too much to repair automatically. \
+                 Run `atuin history init-store` and `atuin store rebuild history`"
+            );
+            report.degraded = true;
+        } else if drift.len() > 0 {
+            tracing::warn!(
+                missing_from_store = report.missing_from_store,
+                missing_from_history = report.missing_from_history,
+                repair,
+                "history db and record store disagree"
+            );
+
+            if repair {
+                for h in drift.missing_from_store {
+                    // The same as `atuin history init-store` does
+                    if h.deleted_at.is_some() {
+                        history_store.delete(h.id).await?;
+                    } else {
+                        history_store.push(h).await?;
+                    }
+                }
+
+                history_db.save_bulk(&drift.missing_from_history).await?;
+                report.repaired = true;
+            }
+        }
+
+        *self.last.lock().expect("consistency report poisoned") = Some(report.clone());
+
+        Ok(report)
+    }
+}
+
+pub async fn worker(settings: Settings, dbs: Databases, consistency: Consistency) {
+    tracing::info!("booting consistency worker");
+
+    // The check reads all history, so don't add to the work of starting up
+    let period = time::Duration::from_secs(settings.daemon.consistency_frequency.max(1));
+    let mut ticker = time::interval_at(time::Instant::now() + period, period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        ticker.tick().await;
+        tracing::info!("consistency worker tick");
+
+        let started = OffsetDateTime::now_utc();
+
+        match consistency.verify(&dbs, true).await {
+            Ok(report) => tracing::info!(
+                ?report,
+                took = (OffsetDateTime::now_utc() - started).to_string(),
+                "consistency check complete"
+            ),
+            Err(e) => {
+                tracing::error!("consistency check failed with {e}");
+                dbs.recover(e.as_ref()).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::OffsetDateTime;
+
+    use atuin_client::history::store::HistoryRecord;
+    use atuin_client::history::History;
+
+    use super::compare;
+
+    fn history(command: &str) -> History {
+        History::daemon()
+            .timestamp(OffsetDateTime::now_utc())
+            .command(command)
+            .cwd("/")
+            .session("session")
+            .hostname("host:user")
+            .build()
+            .into()
+    }
+
+    #[test]
+    fn agreeing_stores_have_no_drift() {
+        let (a, b) = (history("a"), history("b"));
+        let records = vec![
+            HistoryRecord::Create(a.clone()),
+            HistoryRecord::Create(b.clone()),
+        ];
+
+        assert_eq!(compare(vec![a, b], records).len(), 0);
+    }
+
+    #[test]
+    fn rows_without_records_are_missing_from_store() {
+        let (a, b) = (history("a"), history("b"));
+
+        let drift = compare(vec![a.clone(), b.clone()], vec![HistoryRecord::Create(a)]);
+
+        assert_eq!(drift.missing_from_store, vec![b]);
+        assert!(drift.missing_from_history.is_empty());
+    }
+
+    #[test]
+    fn records_not_built_are_missing_from_history() {
+        let (a, b) = (history("a"), history("b"));
+        let records = vec![
+            HistoryRecord::Create(a.clone()),
+            HistoryRecord::Create(b.clone()),
+        ];
+
+        let drift = compare(vec![a], records);
+
+        assert!(drift.missing_from_store.is_empty());
+        assert_eq!(drift.missing_from_history, vec![b]);
+    }
+
+    #[test]
+    fn deleted_history_is_not_missing() {
+        let (a, b) = (history("a"), history("b"));
+
+        // deleted rows are removed from the history db, and may have been deleted before they
+        // were ever created in the store
+        let records = vec![
+            HistoryRecord::Create(a.clone()),
+            HistoryRecord::Delete(a.id),
+            HistoryRecord::Delete(b.id.clone()),
+        ];
+
+        assert_eq!(compare(vec![b], records).len(), 0);
+    }
+}
@@ -0,0 +1,306 @@
+//! The daemon's sqlite handles, reopened when the files go away underneath them.
+//!
+//! If the history DB is on a network mount that briefly disappears, or is replaced by a restore,
+//! the pooled connections keep pointing at the old file and every operation fails until the
+//! daemon restarts. Callers hand their errors to [`Databases::recover`], which reopens both
+//! databases from their paths when the error says the file is gone, and tells the caller whether
+//! to retry.
+//!
+//! A deleted or replaced file doesn't always cause an error: sqlite happily keeps writing to the
+//! unlinked file, and everything written is lost. So before writing, callers also use
+//! [`Databases::check`] to reopen the databases if the files at their paths aren't the ones open.
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use eyre::Result;
+use tokio::sync::{Mutex, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock as AsyncRwLock};
+
+use atuin_client::database::Sqlite as HistoryDatabase;
+use atuin_client::history::store::HistoryStore;
+use atuin_client::record::sqlite_store::SqliteStore;
+use atuin_client::settings::Settings;
+use atuin_common::record::HostId;
+
+/// Reconnect attempts that can fail in a row before the daemon reports itself degraded
+const MAX_FAILED_ATTEMPTS: u32 = 3;
+
+/// A lock that is still held after this many busy timeouts is stuck on a dead connection
+const MAX_LOCKED: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    /// The file was removed, moved, or its mount went away
+    Gone,
+    /// Another connection holds a lock. Usually transient, so only acted on if it persists.
+    Locked,
+}
+
+/// Whether an error comes from sqlite losing its file, rather than from the query
+fn classify(err: &(dyn Error + 'static)) -> Option<Failure> {
+    let mut source = Some(err);
+
+    while let Some(err) = source {
+        let message = err.to_string().to_lowercase();
+
+        if message.contains("disk i/o error")
+            || message.contains("unable to open database file")
+            || message.contains("no such file")
+            // what sqlite says when writing to a file that has been unlinked or moved
+            || message.contains("readonly database")
+        {
+            return Some(Failure::Gone);
+        }
+
+        if message.contains("database is locked") {
+            return Some(Failure::Locked);
+        }
+
+        source = err.source();
+    }
+
+    None
+}
+
+/// Identifies the file at a path, so we can tell when it is replaced
+type FileId = (u64, u64);
+
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+
+    std::fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_path: &Path) -> Option<FileId> {
+    None
+}
+
+#[derive(Debug, Clone)]
+struct Handles {
+    history_db: HistoryDatabase,
+    store: SqliteStore,
+    history_store: HistoryStore,
+    // The files the handles were opened on
+    files: [Option<FileId>; 2],
+}
+
+#[derive(Debug)]
+struct Config {
+    db_path: PathBuf,
+    record_store_path: PathBuf,
+    timeout: f64,
+    host_id: HostId,
+    encryption_key: [u8; 32],
+}
+
+impl Config {
+    fn files(&self) -> [Option<FileId>; 2] {
+        [file_id(&self.db_path), file_id(&self.record_store_path)]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Databases {
+    handles: Arc<RwLock<Handles>>,
+    config: Arc<Config>,
+    // Held while reconnecting, so a burst of failing requests reconnects once
+    reconnecting: Arc<Mutex<()>>,
+    // Held shared while writing a command to both databases, and exclusively while comparing them
+    writes: Arc<AsyncRwLock<()>>,
+    reconnects: Arc<AtomicU64>,
+    failed_attempts: Arc<AtomicU32>,
+    locked: Arc<AtomicU32>,
+}
+
+impl Databases {
+    pub fn new(
+        history_db: HistoryDatabase,
+        store: SqliteStore,
+        settings: &Settings,
+        host_id: HostId,
+        encryption_key: [u8; 32],
+    ) -> Self {
+        let history_store = HistoryStore::new(store.clone(), host_id, encryption_key);
+        let config = Config {
+            db_path: PathBuf::from(settings.db_path.as_str()),
+            record_store_path: PathBuf::from(settings.record_store_path.as_str()),
+            timeout: settings.local_timeout,
+            host_id,
+            encryption_key,
+        };
+
+        Self {
+            handles: Arc::new(RwLock::new(Handles {
+                history_db,
+                store,
+                history_store,
+                files: config.files(),
+            })),
+            config: Arc::new(config),
+            reconnecting: Arc::new(Mutex::new(())),
+            writes: Arc::new(AsyncRwLock::new(())),
+            reconnects: Arc::new(AtomicU64::new(0)),
+            failed_attempts: Arc::new(AtomicU32::new(0)),
+            locked: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    fn handles(&self) -> Handles {
+        self.handles
+            .read()
+            .expect("database handles poisoned")
+            .clone()
+    }
+
+    pub fn history_db(&self) -> HistoryDatabase {
+        self.handles().history_db
+    }
+
+    pub fn store(&self) -> SqliteStore {
+        self.handles().store
+    }
+
+    pub fn history_store(&self) -> HistoryStore {
+        self.handles().history_store
+    }
+
+    /// Hold while writing to both databases, so nothing sees one written without the other
+    pub async fn writing(&self) -> OwnedRwLockReadGuard<()> {
+        self.writes.clone().read_owned().await
+    }
+
+    /// Hold while comparing the databases, so no write is half done
+    pub async fn exclusive(&self) -> OwnedRwLockWriteGuard<()> {
+        self.writes.clone().write_owned().await
+    }
+
+    /// How many times the databases have been reopened
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    /// Reopening the databases keeps failing, so history is not being saved
+    pub fn degraded(&self) -> bool {
+        self.failed_attempts.load(Ordering::Relaxed) >= MAX_FAILED_ATTEMPTS
+    }
+
+    /// Reopen the databases if the files at their paths have been removed or replaced since they
+    /// were opened. Cheap enough to call before every write.
+    pub async fn check(&self) {
+        let open = self
+            .handles
+            .read()
+            .expect("database handles poisoned")
+            .files;
+
+        if open != self.config.files() {
+            tracing::warn!("database files were removed or replaced, reconnecting");
+            self.reconnect().await;
+        }
+    }
+
+    /// Reopen the databases if `err` means their files went away. Returns true if the operation
+    /// that failed should be retried, as the handles have been replaced since it started.
+    pub async fn recover(&self, err: &(dyn Error + Send + Sync + 'static)) -> bool {
+        match classify(err) {
+            None => false,
+            Some(Failure::Locked)
+                if self.locked.fetch_add(1, Ordering::Relaxed) + 1 < MAX_LOCKED =>
+            {
+                false
+            }
+            Some(failure) => {
+                tracing::warn!(?failure, "database error, reconnecting: {err}");
+                self.reconnect().await
+            }
+        }
+    }
+
+    async fn reconnect(&self) -> bool {
+        let seen = self.reconnects();
+        let _reconnecting = self.reconnecting.lock().await;
+
+        // Someone else reconnected while we waited, their handles are as fresh as ours would be
+        if self.reconnects() != seen {
+            return true;
+        }
+
+        match self.open().await {
+            Ok(handles) => {
+                *self.handles.write().expect("database handles poisoned") = handles;
+
+                self.reconnects.fetch_add(1, Ordering::Relaxed);
+                self.failed_attempts.store(0, Ordering::Relaxed);
+                self.locked.store(0, Ordering::Relaxed);
+                tracing::info!("reconnected to databases");
+
+                true
+            }
+            Err(e) => {
+                let failed = self.failed_attempts.fetch_add(1, Ordering::Relaxed) + 1;
+
+                if failed >= MAX_FAILED_ATTEMPTS {
+                    tracing::error!(failed, "failed to reconnect to databases, degraded: {e}");
+                } else {
+                    tracing::warn!(failed, "failed to reconnect to databases: {e}");
+                }
+
+                false
+            }
+        }
+    }
+
+    async fn open(&self) -> Result<Handles> {
+        let config = &self.config;
+
+        let history_db = HistoryDatabase::new(&config.db_path, config.timeout).await?;
+        let store = SqliteStore::new(&config.record_store_path, config.timeout).await?;
+        let history_store = HistoryStore::new(store.clone(), config.host_id, config.encryption_key);
+
+        Ok(Handles {
+            history_db,
+            store,
+            history_store,
+            files: config.files(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, Failure};
+
+    fn sqlite_error(message: &str) -> eyre::Report {
+        eyre::eyre!("error returned from database: {message}")
+    }
+
+    #[test]
+    fn missing_files_are_gone() {
+        for message in [
+            "(code: 10) disk I/O error",
+            "(code: 14) unable to open database file",
+            "(code: 1032) attempt to write a readonly database",
+        ] {
+            let err = sqlite_error(message);
+            assert_eq!(classify(err.as_ref()), Some(Failure::Gone), "{message}");
+        }
+    }
+
+    #[test]
+    fn causes_are_checked() {
+        let err = sqlite_error("(code: 5) database is locked").wrap_err("failed to push record");
+
+        assert_eq!(classify(err.as_ref()), Some(Failure::Locked));
+    }
+
+    #[test]
+    fn query_errors_are_not_reconnected() {
+        let err = sqlite_error("(code: 19) UNIQUE constraint failed: history.id");
+
+        assert_eq!(classify(err.as_ref()), None);
+    }
+}
@@ -0,0 +1,216 @@
+//! State handed to the next daemon on restart: the commands still running, and private mode.
+//!
+//! Without this, restarting the daemon (eg `atuin daemon restart` after an upgrade) would forget
+//! every running command, and their `history end` would fail with "not found". Worse, it would
+//! quietly turn private mode off. The state is written to the data dir after the server stops,
+//! and read back and removed when the next daemon starts.
+//!
+//! It is only written when a restart asks for it, not on every shutdown, and a file too old to
+//! have come from a restart is ignored. Otherwise a daemon started days later would revive
+//! commands whose shells are long gone.
+
+use dashmap::DashMap;
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use atuin_client::history::{History, HistoryId};
+use atuin_client::settings::Settings;
+
+pub const RUNNING_STATE_FILENAME: &str = "daemon_running.json";
+
+/// A restart takes seconds, anything older than this was left by a daemon that stopped for good
+const MAX_AGE: time::Duration = time::Duration::minutes(1);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    saved_at: OffsetDateTime,
+    running: Vec<RunningCommand>,
+    #[serde(default)]
+    private: bool,
+}
+
+/// What the previous daemon left for us
+#[derive(Debug, Default, PartialEq)]
+pub struct Handoff {
+    pub running: Vec<History>,
+    pub private: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RunningCommand {
+    id: String,
+    timestamp: OffsetDateTime,
+    command: String,
+    cwd: String,
+    session: String,
+    hostname: String,
+}
+
+impl From<&History> for RunningCommand {
+    fn from(h: &History) -> Self {
+        Self {
+            id: h.id.0.clone(),
+            timestamp: h.timestamp,
+            command: h.command.clone(),
+            cwd: h.cwd.clone(),
+            session: h.session.clone(),
+            hostname: h.hostname.clone(),
+        }
+    }
+}
+
+impl From<RunningCommand> for History {
+    fn from(c: RunningCommand) -> Self {
+        let mut h: History = History::daemon()
+            .timestamp(c.timestamp)
+            .command(c.command)
+            .cwd(c.cwd)
+            .session(c.session)
+            .hostname(c.hostname)
+            .build()
+            .into();
+
+        // Clients already hold this id, and will end the command with it
+        h.id = HistoryId(c.id);
+
+        h
+    }
+}
+
+fn encode(
+    running: &DashMap<HistoryId, History>,
+    private: bool,
+    saved_at: OffsetDateTime,
+) -> Result<String> {
+    let state = State {
+        saved_at,
+        running: running.iter().map(|h| h.value().into()).collect(),
+        private,
+    };
+
+    Ok(serde_json::to_string(&state)?)
+}
+
+fn decode(value: &str, now: OffsetDateTime) -> Result<Handoff> {
+    let state: State = serde_json::from_str(value)?;
+
+    if now - state.saved_at > MAX_AGE {
+        tracing::info!(
+            saved_at = state.saved_at.to_string(),
+            "ignoring state from a daemon that stopped too long ago"
+        );
+        return Ok(Handoff::default());
+    }
+
+    Ok(Handoff {
+        running: state.running.into_iter().map(History::from).collect(),
+        private: state.private,
+    })
+}
+
+/// Write the state for the next daemon to pick up. Nothing is written if there is nothing to
+/// hand over.
+pub fn save(running: &DashMap<HistoryId, History>, private: bool) -> Result<()> {
+    if running.is_empty() && !private {
+        return Ok(());
+    }
+
+    tracing::info!(
+        count = running.len(),
+        private,
+        "saving state for the next daemon"
+    );
+    let value = encode(running, private, OffsetDateTime::now_utc())?;
+    Settings::save_to_data_dir(RUNNING_STATE_FILENAME, &value)
+}
+
+/// Take the state left by the previous daemon, if any. The file is removed, so it is only
+/// restored once.
+pub fn load() -> Handoff {
+    let Some(value) = Settings::read_from_data_dir(RUNNING_STATE_FILENAME) else {
+        return Handoff::default();
+    };
+
+    let path = atuin_common::utils::data_dir().join(RUNNING_STATE_FILENAME);
+    if let Err(e) = std::fs::remove_file(path) {
+        tracing::warn!("failed to remove running commands file: {e}");
+    }
+
+    decode(&value, OffsetDateTime::now_utc()).unwrap_or_else(|e| {
+        tracing::warn!("ignoring unreadable running commands file: {e}");
+        Handoff::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use dashmap::DashMap;
+    use time::{Duration, OffsetDateTime};
+
+    use atuin_client::history::{History, HistoryId};
+
+    use super::{decode, encode, Handoff};
+
+    fn running() -> (DashMap<HistoryId, History>, History) {
+        let h: History = History::daemon()
+            .timestamp(OffsetDateTime::now_utc())
+            .command("sleep 100")
+            .cwd("/home/ellie")
+            .session("session")
+            .hostname("host:ellie")
+            .build()
+            .into();
+
+        let running = DashMap::new();
+        running.insert(h.id.clone(), h.clone());
+
+        (running, h)
+    }
+
+    #[test]
+    fn roundtrip_keeps_ids() {
+        let (running, h) = running();
+        let now = OffsetDateTime::now_utc();
+
+        let restored = decode(
+            &encode(&running, false, now).unwrap(),
+            now + Duration::seconds(5),
+        )
+        .unwrap();
+
+        assert_eq!(restored.running, vec![h]);
+        assert!(!restored.private);
+    }
+
+    #[test]
+    fn roundtrip_keeps_private_mode() {
+        let now = OffsetDateTime::now_utc();
+
+        let restored = decode(&encode(&DashMap::new(), true, now).unwrap(), now).unwrap();
+
+        assert!(restored.private);
+    }
+
+    #[test]
+    fn stale_file_is_ignored() {
+        let (running, _) = running();
+        let now = OffsetDateTime::now_utc();
+
+        let restored = decode(
+            &encode(&running, true, now).unwrap(),
+            now + Duration::hours(1),
+        )
+        .unwrap();
+
+        assert_eq!(restored, Handoff::default());
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        let now = OffsetDateTime::now_utc();
+
+        assert!(decode("not json", now).is_err());
+        assert!(decode("[{\"id\": 1}]", now).is_err());
+    }
+}
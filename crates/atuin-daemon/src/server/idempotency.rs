@@ -0,0 +1,85 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use atuin_client::history::HistoryId;
+
+// Retries arrive within moments of the original request, so only a handful need remembering
+const MAX_RECENT_STARTS: usize = 256;
+
+/// The ids handed out for recent `start_history` idempotency keys, so that a client retrying a
+/// start gets the same id back rather than a second running entry for one command.
+#[derive(Debug, Clone, Default)]
+pub struct RecentStarts(Arc<Mutex<Keys>>);
+
+#[derive(Debug, Default)]
+struct Keys {
+    ids: HashMap<String, HistoryId>,
+    order: VecDeque<String>,
+}
+
+impl RecentStarts {
+    /// Return the id already started for `key`, or start a new one with `start` and remember it.
+    /// The lock is held throughout, so concurrent retries can't both start.
+    pub fn get_or_start(&self, key: String, start: impl FnOnce() -> HistoryId) -> HistoryId {
+        let mut keys = self.0.lock().expect("idempotency keys lock poisoned");
+
+        if let Some(id) = keys.ids.get(&key) {
+            return id.clone();
+        }
+
+        let id = start();
+
+        if keys.order.len() >= MAX_RECENT_STARTS {
+            if let Some(oldest) = keys.order.pop_front() {
+                keys.ids.remove(&oldest);
+            }
+        }
+
+        keys.order.push_back(key.clone());
+        keys.ids.insert(key, id.clone());
+
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atuin_client::history::HistoryId;
+
+    use super::{RecentStarts, MAX_RECENT_STARTS};
+
+    fn id(i: usize) -> HistoryId {
+        HistoryId(format!("id-{i}"))
+    }
+
+    #[test]
+    fn repeated_key_returns_first_id() {
+        let recent = RecentStarts::default();
+
+        let first = recent.get_or_start("key".to_string(), || id(1));
+        let second = recent.get_or_start("key".to_string(), || panic!("started twice"));
+
+        assert_eq!(first, id(1));
+        assert_eq!(second, id(1));
+    }
+
+    #[test]
+    fn different_keys_start_separately() {
+        let recent = RecentStarts::default();
+
+        assert_eq!(recent.get_or_start("a".to_string(), || id(1)), id(1));
+        assert_eq!(recent.get_or_start("b".to_string(), || id(2)), id(2));
+    }
+
+    #[test]
+    fn oldest_key_is_forgotten() {
+        let recent = RecentStarts::default();
+
+        for i in 0..=MAX_RECENT_STARTS {
+            recent.get_or_start(i.to_string(), || id(i));
+        }
+
+        assert_eq!(recent.get_or_start("1".to_string(), || id(999)), id(1));
+        assert_eq!(recent.get_or_start("0".to_string(), || id(999)), id(999));
+    }
+}
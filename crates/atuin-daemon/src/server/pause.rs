@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+
+use time::OffsetDateTime;
+
+/// Background sync is paused until this time, for maintenance windows such as large imports.
+/// History is always recorded, as that's user data. The pause expires on its own, so a
+/// forgotten pause can't disable sync forever.
+#[derive(Debug, Clone, Default)]
+pub struct Pause(Arc<AtomicI64>);
+
+impl Pause {
+    pub fn until(&self, until: OffsetDateTime) {
+        self.0.store(until.unix_timestamp(), Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+
+    /// When the pause ends, or None if not currently paused
+    pub fn paused_until(&self, now: OffsetDateTime) -> Option<OffsetDateTime> {
+        let until = self.0.load(Ordering::Relaxed);
+
+        (until > now.unix_timestamp())
+            .then(|| OffsetDateTime::from_unix_timestamp(until).ok())
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{Duration, OffsetDateTime};
+
+    use super::Pause;
+
+    #[test]
+    fn not_paused_by_default() {
+        assert_eq!(
+            Pause::default().paused_until(OffsetDateTime::now_utc()),
+            None
+        );
+    }
+
+    #[test]
+    fn pause_expires() {
+        let now = OffsetDateTime::now_utc();
+        let pause = Pause::default();
+        pause.until(now + Duration::minutes(5));
+
+        assert!(pause.paused_until(now).is_some());
+        assert!(pause.paused_until(now + Duration::minutes(4)).is_some());
+        assert_eq!(pause.paused_until(now + Duration::minutes(5)), None);
+    }
+
+    #[test]
+    fn resume_clears_pause() {
+        let now = OffsetDateTime::now_utc();
+        let pause = Pause::default();
+        pause.until(now + Duration::hours(1));
+        pause.resume();
+
+        assert_eq!(pause.paused_until(now), None);
+    }
+}
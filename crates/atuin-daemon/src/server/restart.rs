@@ -0,0 +1,86 @@
+//! What the daemon does once the server has stopped: exit, hand its state to the next daemon, or
+//! exec a new binary in place.
+//!
+//! Restarting in place keeps the listening socket open across the exec, passed on with the same
+//! `LISTEN_FDS` protocol systemd uses, so clients never see the socket missing or refusing
+//! connections. Connections made while the new binary starts up wait in the socket's backlog.
+
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Set on the exec'd daemon, telling it to take over the listening socket rather than bind one
+pub const HANDOFF_ENV: &str = "ATUIN_DAEMON_HANDOFF";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Exit {
+    /// Just exit, saving nothing
+    #[default]
+    Stop,
+
+    /// Another daemon is about to be started, save the running commands for it
+    Handoff,
+
+    /// Exec this binary in place, passing on the listening socket and the running commands
+    #[cfg(unix)]
+    Exec(PathBuf),
+}
+
+/// Decided by the Shutdown and Restart RPCs, and acted on once the server has stopped
+#[derive(Debug, Clone, Default)]
+pub struct ExitPlan(Arc<Mutex<Exit>>);
+
+impl ExitPlan {
+    pub fn set(&self, exit: Exit) {
+        *self.0.lock().expect("exit plan lock poisoned") = exit;
+    }
+
+    pub fn get(&self) -> Exit {
+        self.0.lock().expect("exit plan lock poisoned").clone()
+    }
+}
+
+/// Replace this process with `binary daemon`, handing it `listener`. Only returns if that failed.
+#[cfg(unix)]
+pub fn exec(binary: &std::path::Path, listener: &std::os::unix::net::UnixListener) -> eyre::Report {
+    use std::os::fd::{AsFd, AsRawFd};
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    use rustix::io::{fcntl_setfd, FdFlags};
+
+    // Everything else is close-on-exec, this is the one fd the new binary should inherit
+    if let Err(e) = fcntl_setfd(listener.as_fd(), FdFlags::empty()) {
+        return eyre::eyre!("failed to pass on the listening socket: {e}");
+    }
+
+    tracing::info!(binary = ?binary, "restarting in place");
+
+    let err = Command::new(binary)
+        .arg("daemon")
+        .env("LISTEN_FDS", "1")
+        .env("LISTEN_FDS_FIRST_FD", listener.as_raw_fd().to_string())
+        .env("LISTEN_PID", std::process::id().to_string())
+        .env(HANDOFF_ENV, "1")
+        .exec();
+
+    eyre::eyre!("failed to exec {binary:?}: {err}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Exit, ExitPlan};
+
+    #[test]
+    fn plan_defaults_to_stop() {
+        assert_eq!(ExitPlan::default().get(), Exit::Stop);
+    }
+
+    #[test]
+    fn plan_is_shared() {
+        let plan = ExitPlan::default();
+        plan.clone().set(Exit::Handoff);
+
+        assert_eq!(plan.get(), Exit::Handoff);
+    }
+}
@@ -1,29 +1,55 @@
+use ::time::OffsetDateTime;
 use eyre::Result;
 use rand::Rng;
 use tokio::time::{self, MissedTickBehavior};
 
-use atuin_client::database::Sqlite as HistoryDatabase;
-use atuin_client::{
-    encryption,
-    history::store::HistoryStore,
-    record::{sqlite_store::SqliteStore, sync},
-    settings::Settings,
-};
+use atuin_client::{encryption, record::sync, settings::Settings};
 
 use atuin_dotfiles::store::{var::VarStore, AliasStore};
 
-pub async fn worker(
-    settings: Settings,
-    store: SqliteStore,
-    history_store: HistoryStore,
-    history_db: HistoryDatabase,
+use super::db::Databases;
+use super::pause::Pause;
+
+/// Sync the record store, then build what was downloaded into the history DB and dotfiles
+async fn sync_once(
+    settings: &Settings,
+    dbs: &Databases,
+    host_id: atuin_common::record::HostId,
+    encryption_key: [u8; 32],
 ) -> Result<()> {
+    dbs.check().await;
+
+    // Fetched every tick, so a tick after a reconnect uses the new handles
+    let store = dbs.store();
+    let history_db = dbs.history_db();
+
+    let (uploaded, downloaded) = sync::sync(settings, &store).await?;
+
+    tracing::info!(
+        uploaded = ?uploaded,
+        downloaded = ?downloaded,
+        "sync complete"
+    );
+
+    dbs.history_store()
+        .incremental_build(&history_db, &downloaded)
+        .await?;
+
+    AliasStore::new(store.clone(), host_id, encryption_key)
+        .build()
+        .await?;
+    VarStore::new(store, host_id, encryption_key)
+        .build()
+        .await?;
+
+    Ok(())
+}
+
+pub async fn worker(settings: Settings, dbs: Databases, pause: Pause) -> Result<()> {
     tracing::info!("booting sync worker");
 
     let encryption_key: [u8; 32] = encryption::load_key(&settings)?.into();
     let host_id = Settings::host_id().expect("failed to get host_id");
-    let alias_store = AliasStore::new(store.clone(), host_id, encryption_key);
-    let var_store = VarStore::new(store.clone(), host_id, encryption_key);
 
     // Don't backoff by more than 30 mins (with a random jitter of up to 1 min)
     let max_interval: f64 = 60.0 * 30.0 + rand::thread_rng().gen_range(0.0..60.0);
@@ -43,11 +69,21 @@ pub async fn worker(
             continue;
         }
 
-        let res = sync::sync(&settings, &store).await;
+        if let Some(until) = pause.paused_until(OffsetDateTime::now_utc()) {
+            tracing::info!(until = until.to_string(), "sync paused, skipping sync tick");
+            continue;
+        }
+
+        let res = sync_once(&settings, &dbs, host_id, encryption_key).await;
 
         if let Err(e) = res {
             tracing::error!("sync tick failed with {e}");
 
+            // A lost database only needs reopening, the backoff below is for the server
+            if dbs.recover(e.as_ref()).await {
+                continue;
+            }
+
             let mut rng = rand::thread_rng();
 
             let mut new_interval = ticker.period().as_secs_f64() * rng.gen_range(2.0..2.2);
@@ -61,21 +97,6 @@ pub async fn worker(
 
             tracing::error!("backing off, next sync tick in {new_interval}");
         } else {
-            let (uploaded, downloaded) = res.unwrap();
-
-            tracing::info!(
-                uploaded = ?uploaded,
-                downloaded = ?downloaded,
-                "sync complete"
-            );
-
-            history_store
-                .incremental_build(&history_db, &downloaded)
-                .await?;
-
-            alias_store.build().await?;
-            var_store.build().await?;
-
             // Reset backoff on success
             if ticker.period().as_secs() != settings.daemon.sync_frequency {
                 ticker = time::interval(time::Duration::from_secs(settings.daemon.sync_frequency));
@@ -0,0 +1,263 @@
+use std::process::Stdio;
+use std::sync::Arc;
+
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+
+use atuin_client::history::History;
+use atuin_client::settings::{Watch, WatchExit};
+
+// Don't let a burst of finished commands fork an unbounded number of processes
+const MAX_CONCURRENT_WATCH_COMMANDS: usize = 4;
+
+/// Runs the configured `daemon.watch` commands when a finished command matches their rule
+///
+/// Rules only ever come from the local config file, never from synced data.
+#[derive(Debug, Clone)]
+pub struct Watcher {
+    rules: Arc<Vec<Watch>>,
+    permits: Arc<Semaphore>,
+}
+
+impl Watcher {
+    pub fn new(rules: Vec<Watch>) -> Self {
+        Self {
+            rules: Arc::new(rules),
+            permits: Arc::new(Semaphore::new(MAX_CONCURRENT_WATCH_COMMANDS)),
+        }
+    }
+
+    /// Spawn the command for every rule matching this history. Does not wait for them to run.
+    /// Returns how many were started.
+    pub fn trigger(&self, history: &History) -> usize {
+        let mut started = 0;
+
+        for rule in self.rules.iter().filter(|r| matches(r, history)) {
+            // Drop rather than queue when busy, so a burst of matches can't pile up tasks
+            let Ok(permit) = self.permits.clone().try_acquire_owned() else {
+                tracing::warn!(
+                    rule = rule.name,
+                    id = history.id.0,
+                    "too many watch commands running, skipping"
+                );
+                continue;
+            };
+
+            tracing::info!(rule = rule.name, id = history.id.0, "watch rule triggered");
+
+            let mut cmd = shell_command(&rule.exec);
+            cmd.envs(env(rule, history))
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .kill_on_drop(true);
+
+            let name = rule.name.clone();
+
+            tokio::spawn(async move {
+                let _permit = permit;
+
+                match cmd.status().await {
+                    Ok(status) if !status.success() => {
+                        tracing::warn!(rule = name, "watch command failed with {status}");
+                    }
+                    Err(e) => tracing::warn!(rule = name, "failed to run watch command: {e}"),
+                    _ => {}
+                }
+            });
+
+            started += 1;
+        }
+
+        started
+    }
+}
+
+fn matches(rule: &Watch, history: &History) -> bool {
+    let exit = match rule.exit {
+        WatchExit::Any => true,
+        WatchExit::Success => history.exit == 0,
+        WatchExit::Failure => history.exit != 0,
+    };
+
+    let duration = u64::try_from(history.duration)
+        .is_ok_and(|d| d >= rule.min_duration_seconds.saturating_mul(1_000_000_000));
+
+    let command = rule
+        .command
+        .as_ref()
+        .map_or(true, |re| re.is_match(&history.command));
+
+    exit && duration && command
+}
+
+fn env(rule: &Watch, history: &History) -> Vec<(&'static str, String)> {
+    vec![
+        ("ATUIN_WATCH_NAME", rule.name.clone()),
+        ("ATUIN_HISTORY_ID", history.id.0.clone()),
+        ("ATUIN_COMMAND", history.command.clone()),
+        ("ATUIN_CWD", history.cwd.clone()),
+        ("ATUIN_SESSION", history.session.clone()),
+        ("ATUIN_HOSTNAME", history.hostname.clone()),
+        ("ATUIN_EXIT", history.exit.to_string()),
+        ("ATUIN_DURATION", history.duration.to_string()),
+    ]
+}
+
+#[cfg(unix)]
+fn shell_command(exec: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(exec);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell_command(exec: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(exec);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+    use time::OffsetDateTime;
+
+    use super::*;
+
+    fn rule() -> Watch {
+        Watch {
+            name: "test".to_string(),
+            command: None,
+            min_duration_seconds: 0,
+            exit: WatchExit::Any,
+            exec: "true".to_string(),
+        }
+    }
+
+    fn history(command: &str, exit: i64, duration_secs: i64) -> History {
+        let mut h: History = History::daemon()
+            .timestamp(OffsetDateTime::now_utc())
+            .command(command)
+            .cwd("/home/ellie")
+            .session("session")
+            .hostname("host:ellie")
+            .build()
+            .into();
+
+        h.exit = exit;
+        h.duration = duration_secs * 1_000_000_000;
+
+        h
+    }
+
+    #[test]
+    fn empty_rule_matches_everything() {
+        assert!(matches(&rule(), &history("ls", 0, 0)));
+        assert!(matches(&rule(), &history("false", 1, 10)));
+    }
+
+    #[test]
+    fn command_regex() {
+        let rule = Watch {
+            command: Some(Regex::new("^terraform apply").unwrap()),
+            ..rule()
+        };
+
+        assert!(matches(
+            &rule,
+            &history("terraform apply -auto-approve", 0, 0)
+        ));
+        assert!(!matches(&rule, &history("terraform plan", 0, 0)));
+        assert!(!matches(&rule, &history("echo terraform apply", 0, 0)));
+    }
+
+    #[test]
+    fn min_duration() {
+        let rule = Watch {
+            min_duration_seconds: 300,
+            ..rule()
+        };
+
+        assert!(matches(&rule, &history("make", 0, 300)));
+        assert!(matches(&rule, &history("make", 0, 3600)));
+        assert!(!matches(&rule, &history("make", 0, 299)));
+    }
+
+    #[test]
+    fn exit_filter() {
+        let failure = Watch {
+            exit: WatchExit::Failure,
+            ..rule()
+        };
+        let success = Watch {
+            exit: WatchExit::Success,
+            ..rule()
+        };
+
+        assert!(matches(&failure, &history("false", 1, 0)));
+        assert!(!matches(&failure, &history("true", 0, 0)));
+        assert!(matches(&success, &history("true", 0, 0)));
+        assert!(!matches(&success, &history("false", 127, 0)));
+    }
+
+    #[test]
+    fn all_conditions_must_match() {
+        let rule = Watch {
+            command: Some(Regex::new("^cargo").unwrap()),
+            min_duration_seconds: 60,
+            exit: WatchExit::Failure,
+            ..rule()
+        };
+
+        assert!(matches(&rule, &history("cargo build", 101, 90)));
+        assert!(!matches(&rule, &history("cargo build", 0, 90)));
+        assert!(!matches(&rule, &history("cargo build", 101, 30)));
+        assert!(!matches(&rule, &history("make", 101, 90)));
+    }
+
+    #[test]
+    fn env_describes_history() {
+        let h = history("cargo test", 101, 2);
+        let env = env(&rule(), &h);
+
+        let get = |key: &str| env.iter().find(|(k, _)| *k == key).unwrap().1.clone();
+
+        assert_eq!(get("ATUIN_WATCH_NAME"), "test");
+        assert_eq!(get("ATUIN_HISTORY_ID"), h.id.0);
+        assert_eq!(get("ATUIN_COMMAND"), "cargo test");
+        assert_eq!(get("ATUIN_CWD"), "/home/ellie");
+        assert_eq!(get("ATUIN_EXIT"), "101");
+        assert_eq!(get("ATUIN_DURATION"), "2000000000");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn trigger_does_not_wait_for_the_command() {
+        let watcher = Watcher::new(vec![Watch {
+            exec: "sleep 10".to_string(),
+            ..rule()
+        }]);
+
+        let start = std::time::Instant::now();
+        watcher.trigger(&history("ls", 0, 0));
+
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn burst_past_the_limit_is_dropped() {
+        let watcher = Watcher::new(vec![Watch {
+            exec: "sleep 10".to_string(),
+            ..rule()
+        }]);
+
+        let started: usize = (0..MAX_CONCURRENT_WATCH_COMMANDS * 3)
+            .map(|_| watcher.trigger(&history("ls", 0, 0)))
+            .sum();
+
+        assert_eq!(started, MAX_CONCURRENT_WATCH_COMMANDS);
+        assert_eq!(watcher.permits.available_permits(), 0);
+    }
+}
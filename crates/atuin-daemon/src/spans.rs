@@ -0,0 +1,188 @@
+//! Span timings for profiling the daemon, written to `daemon.span_file`.
+//!
+//! Every span that closes becomes one line of JSON, with its name, target, fields, parent and how
+//! long it was open for. This is independent of `ATUIN_LOG`, so timings can be collected without
+//! also turning on trace logging.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use eyre::{Result, WrapErr};
+use serde_json::{json, Map, Value};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+pub struct SpanFile<W = LineWriter<File>> {
+    writer: Mutex<W>,
+}
+
+impl SpanFile {
+    /// Append span timings to the file at `path`, creating it if needed
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .wrap_err_with(|| format!("could not open span file {}", path.display()))?;
+
+        Ok(Self::new(LineWriter::new(file)))
+    }
+}
+
+impl<W: Write> SpanFile<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+struct Timing {
+    start: Instant,
+    started_at: OffsetDateTime,
+}
+
+struct Fields(Map<String, Value>);
+
+impl Visit for Fields {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}").into());
+    }
+}
+
+impl<S, W> Layer<S> for SpanFile<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: Write + Send + 'static,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut fields = Fields(Map::new());
+        attrs.record(&mut fields);
+
+        let mut extensions = span.extensions_mut();
+        extensions.insert(fields);
+        extensions.insert(Timing {
+            start: Instant::now(),
+            started_at: OffsetDateTime::now_utc(),
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+
+        let extensions = span.extensions();
+        let Some(timing) = extensions.get::<Timing>() else {
+            return;
+        };
+
+        let line = json!({
+            "name": span.name(),
+            "target": span.metadata().target(),
+            "start": timing.started_at.format(&Rfc3339).ok(),
+            "duration_us": u64::try_from(timing.start.elapsed().as_micros()).unwrap_or(u64::MAX),
+            "parent": span.parent().map(|p| p.name()),
+            "fields": extensions.get::<Fields>().map(|f| &f.0),
+        });
+
+        // Profiling must never take the daemon down, so a failed write just loses the line
+        if let Ok(mut writer) = self.writer.lock() {
+            let _ = writeln!(writer, "{line}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::Value;
+    use tracing_subscriber::prelude::*;
+
+    use super::SpanFile;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn closed_spans_are_written_as_json_lines() {
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::registry().with(SpanFile::new(buffer.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let outer = tracing::info_span!("end_history", id = "abc");
+            let _outer = outer.enter();
+
+            let inner = tracing::info_span!("save", idx = tracing::field::Empty);
+            inner.record("idx", 3);
+            inner.in_scope(|| {});
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+
+        assert_eq!(lines[0]["name"], "save");
+        assert_eq!(lines[0]["parent"], "end_history");
+        assert_eq!(lines[0]["fields"]["idx"], 3);
+
+        assert_eq!(lines[1]["name"], "end_history");
+        assert_eq!(lines[1]["parent"], Value::Null);
+        assert_eq!(lines[1]["fields"]["id"], "abc");
+        assert!(lines[1]["duration_us"].is_u64());
+    }
+}
@@ -0,0 +1,257 @@
+//! A tiny on-disk record of whether the daemon was answering last time we checked.
+//!
+//! `atuin daemon warm` is run in the background when a shell starts. It asks the daemon for its
+//! status, and writes this file along with the daemon's protocol version. The
+//! hot `history start`/`history end` paths read it, so that they can skip a connection attempt
+//! that is known to fail. The "down" state expires quickly, so a restarted daemon is picked up
+//! again within a few seconds.
+
+use std::future::Future;
+
+use eyre::{bail, Result};
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
+
+use atuin_client::settings::Settings;
+
+pub const DAEMON_STATE_FILENAME: &str = "daemon_state";
+
+/// How long a "down" observation is trusted for
+pub const DOWN_TTL: Duration = Duration::seconds(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DaemonState {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Observation {
+    pub state: DaemonState,
+    pub checked_at: OffsetDateTime,
+    /// The protocol version the daemon reported, when it was up
+    pub version: Option<String>,
+}
+
+impl Observation {
+    pub fn new(state: DaemonState, version: Option<String>) -> Self {
+        Self {
+            state,
+            checked_at: OffsetDateTime::now_utc(),
+            version,
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split_whitespace();
+        let state = parts.next()?;
+        let checked_at = parts.next()?;
+        let version = parts.next().map(String::from);
+
+        let state = match state {
+            "up" => DaemonState::Up,
+            "down" => DaemonState::Down,
+            _ => return None,
+        };
+
+        let checked_at = OffsetDateTime::parse(checked_at, &Rfc3339).ok()?;
+
+        Some(Self {
+            state,
+            checked_at,
+            version,
+        })
+    }
+
+    fn encode(&self) -> Result<String> {
+        let state = match self.state {
+            DaemonState::Up => "up",
+            DaemonState::Down => "down",
+        };
+
+        let checked_at = self.checked_at.format(&Rfc3339)?;
+
+        Ok(match &self.version {
+            Some(version) => format!("{state} {checked_at} {version}"),
+            None => format!("{state} {checked_at}"),
+        })
+    }
+
+    /// Whether this observation says the daemon is down, and is recent enough to act on
+    pub fn known_down(&self, now: OffsetDateTime) -> bool {
+        self.state == DaemonState::Down && now - self.checked_at < DOWN_TTL
+    }
+}
+
+pub fn read() -> Option<Observation> {
+    Settings::read_from_data_dir(DAEMON_STATE_FILENAME).and_then(|v| Observation::parse(&v))
+}
+
+pub fn record(state: DaemonState, version: Option<String>) -> Result<()> {
+    Settings::save_to_data_dir(
+        DAEMON_STATE_FILENAME,
+        &Observation::new(state, version).encode()?,
+    )
+}
+
+/// Connect to the daemon with `connect`, unless it was recently seen to be unreachable. A failed
+/// connection is recorded, so that the next few attempts are skipped.
+pub async fn connect<T, F, Fut>(connect: F) -> Result<T>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    connect_with(read(), OffsetDateTime::now_utc(), connect, |state| {
+        let _ = record(state, None);
+    })
+    .await
+}
+
+async fn connect_with<T, F, Fut>(
+    observed: Option<Observation>,
+    now: OffsetDateTime,
+    connect: F,
+    record: impl FnOnce(DaemonState),
+) -> Result<T>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    if observed.is_some_and(|o| o.known_down(now)) {
+        bail!("atuin daemon was unreachable moments ago. Is it running?");
+    }
+
+    connect().await.inspect_err(|_| record(DaemonState::Down))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use eyre::eyre;
+    use time::Duration;
+
+    use super::*;
+
+    // Stands in for HistoryClient::new, counting the connection attempts
+    async fn count_connect(connects: &Cell<usize>, up: bool) -> Result<()> {
+        connects.set(connects.get() + 1);
+
+        if up {
+            Ok(())
+        } else {
+            Err(eyre!("connection refused"))
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let obs = Observation::new(DaemonState::Down, None);
+        let parsed = Observation::parse(&obs.encode().unwrap()).unwrap();
+
+        assert_eq!(parsed.state, DaemonState::Down);
+        assert_eq!(
+            parsed.checked_at.unix_timestamp(),
+            obs.checked_at.unix_timestamp()
+        );
+    }
+
+    #[test]
+    fn roundtrip_with_version() {
+        let obs = Observation::new(DaemonState::Up, Some("0.3.0".to_string()));
+        let parsed = Observation::parse(&obs.encode().unwrap()).unwrap();
+
+        assert_eq!(parsed.state, DaemonState::Up);
+        assert_eq!(parsed.version.as_deref(), Some("0.3.0"));
+    }
+
+    #[test]
+    fn garbage_is_ignored() {
+        assert_eq!(Observation::parse(""), None);
+        assert_eq!(Observation::parse("sideways 2024-01-01T00:00:00Z"), None);
+        assert_eq!(Observation::parse("up yesterday"), None);
+    }
+
+    #[test]
+    fn down_expires() {
+        let obs = Observation::new(DaemonState::Down, None);
+
+        assert!(obs.known_down(obs.checked_at + Duration::seconds(1)));
+        assert!(!obs.known_down(obs.checked_at + DOWN_TTL));
+    }
+
+    #[test]
+    fn up_never_skips() {
+        let obs = Observation::new(DaemonState::Up, None);
+
+        assert!(!obs.known_down(obs.checked_at));
+    }
+
+    #[tokio::test]
+    async fn warm_state_connects_once() {
+        let connects = Cell::new(0);
+        let obs = Observation::new(DaemonState::Up, None);
+
+        connect_with(
+            Some(obs.clone()),
+            obs.checked_at,
+            || count_connect(&connects, true),
+            |_| panic!("nothing should be recorded"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(connects.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn known_down_does_not_connect() {
+        let connects = Cell::new(0);
+        let obs = Observation::new(DaemonState::Down, None);
+
+        let res = connect_with(
+            Some(obs.clone()),
+            obs.checked_at + Duration::seconds(1),
+            || count_connect(&connects, true),
+            |_| {},
+        )
+        .await;
+
+        assert!(res.is_err());
+        assert_eq!(connects.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn expired_down_connects_again() {
+        let connects = Cell::new(0);
+        let obs = Observation::new(DaemonState::Down, None);
+
+        connect_with(
+            Some(obs.clone()),
+            obs.checked_at + DOWN_TTL,
+            || count_connect(&connects, true),
+            |_| {},
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(connects.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_connect_is_recorded() {
+        let connects = Cell::new(0);
+        let recorded = Cell::new(None);
+
+        let res = connect_with(
+            None,
+            OffsetDateTime::now_utc(),
+            || count_connect(&connects, false),
+            |state| recorded.set(Some(state)),
+        )
+        .await;
+
+        assert!(res.is_err());
+        assert_eq!(connects.get(), 1);
+        assert_eq!(recorded.get(), Some(DaemonState::Down));
+    }
+}
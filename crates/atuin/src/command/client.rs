@@ -4,7 +4,14 @@ use clap::Subcommand;
 use eyre::{Result, WrapErr};
 
 use atuin_client::{database::Sqlite, record::sqlite_store::SqliteStore, settings::Settings};
-use tracing_subscriber::{filter::EnvFilter, fmt, prelude::*};
+use tracing_subscriber::{filter::EnvFilter, fmt, prelude::*, Layer};
+
+#[cfg(feature = "daemon")]
+use atuin_daemon::spans::SpanFile;
+#[cfg(feature = "daemon")]
+use tracing::Level;
+#[cfg(feature = "daemon")]
+use tracing_subscriber::filter::Targets;
 
 #[cfg(feature = "sync")]
 mod sync;
@@ -15,6 +22,9 @@ mod account;
 #[cfg(feature = "daemon")]
 mod daemon;
 
+#[cfg(feature = "daemon")]
+mod privacy;
+
 mod default_config;
 mod doctor;
 mod dotfiles;
@@ -79,7 +89,12 @@ pub enum Cmd {
     /// *Experimental* Start the background daemon
     #[cfg(feature = "daemon")]
     #[command()]
-    Daemon,
+    Daemon(daemon::Cmd),
+
+    /// Pause or resume recording history in the daemon
+    #[cfg(feature = "daemon")]
+    #[command(subcommand)]
+    Privacy(privacy::Cmd),
 
     /// Print the default atuin configuration (config.toml)
     #[command()]
@@ -101,14 +116,41 @@ impl Cmd {
         res
     }
 
+    /// When running the daemon with `daemon.span_file` set, a layer writing every atuin span's
+    /// timing to that file. It has its own filter, so doesn't depend on `ATUIN_LOG`.
+    #[cfg(feature = "daemon")]
+    fn daemon_span_layer<S>(&self, settings: &Settings) -> Result<Option<impl Layer<S>>>
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        let Self::Daemon(daemon::Cmd {
+            subcmd: None | Some(daemon::SubCmd::Run),
+        }) = self
+        else {
+            return Ok(None);
+        };
+
+        let Some(path) = &settings.daemon.span_file else {
+            return Ok(None);
+        };
+
+        let targets = Targets::new()
+            .with_target("atuin_daemon", Level::TRACE)
+            .with_target("atuin_client", Level::TRACE);
+
+        Ok(Some(SpanFile::create(path.as_ref())?.with_filter(targets)))
+    }
+
     async fn run_inner(self, mut settings: Settings) -> Result<()> {
         let filter =
             EnvFilter::from_env("ATUIN_LOG").add_directive("sqlx_sqlite::regexp=off".parse()?);
 
-        tracing_subscriber::registry()
-            .with(fmt::layer())
-            .with(filter)
-            .init();
+        let registry = tracing_subscriber::registry().with(fmt::layer().with_filter(filter));
+
+        #[cfg(feature = "daemon")]
+        let registry = registry.with(self.daemon_span_layer(&settings)?);
+
+        registry.init();
 
         tracing::trace!(command = ?self, "client command");
 
@@ -118,6 +160,13 @@ impl Cmd {
         match self {
             Self::History(history) => return history.run(&settings).await,
             Self::Init(init) => return init.run(&settings).await,
+
+            #[cfg(feature = "daemon")]
+            Self::Privacy(privacy) => return privacy.run(&settings).await,
+
+            #[cfg(feature = "daemon")]
+            Self::Daemon(daemon) => return daemon.run(settings).await,
+
             _ => {}
         }
 
@@ -156,9 +205,6 @@ impl Cmd {
                 Ok(())
             }
 
-            #[cfg(feature = "daemon")]
-            Self::Daemon => daemon::run(settings, sqlite_store, db).await,
-
             _ => unimplemented!(),
         }
     }
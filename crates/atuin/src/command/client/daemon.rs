@@ -1,10 +1,436 @@
-use eyre::Result;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use time::OffsetDateTime;
+
+use clap::{Args, Subcommand};
+use eyre::{bail, OptionExt, Result, WrapErr};
+use sysinfo::System;
 
 use atuin_client::{database::Sqlite, record::sqlite_store::SqliteStore, settings::Settings};
-use atuin_daemon::server::listen;
+use atuin_daemon::{
+    client::{HistoryClient, Unsupported},
+    server::listen,
+    warm::{self, DaemonState},
+};
+
+use super::search::format_duration;
+
+/// Where `atuin daemon restart` sends the output of the daemon it starts, in the data dir
+const DAEMON_LOG_FILENAME: &str = "daemon.log";
+
+#[derive(Args, Debug)]
+pub struct Cmd {
+    #[command(subcommand)]
+    pub subcmd: Option<SubCmd>,
+}
+
+#[derive(Subcommand, Debug)]
+#[command(infer_subcommands = true)]
+pub enum SubCmd {
+    /// Run the daemon in the foreground (the default)
+    Run,
+
+    /// Connect to the daemon and record whether it is reachable. Run by the shell init scripts
+    #[command(hide = true)]
+    Warm,
+
+    /// Print the status of the running daemon
+    Status,
+
+    /// Stop the running daemon and start it again in the background, eg after an upgrade
+    Restart {
+        /// Restart in place, keeping the daemon's pid and socket, so shells never find it missing
+        #[arg(long)]
+        graceful: bool,
+
+        /// The atuin binary to run the daemon from. Defaults to this one
+        #[arg(long)]
+        binary: Option<PathBuf>,
+    },
+
+    /// Pause background sync, eg during a large import. History is still recorded
+    Pause {
+        /// How long to pause for, eg "30m". Defaults to, and is capped at, the configured maximum
+        #[arg(long = "for", value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+    },
+
+    /// Resume background sync after a pause
+    Resume,
+
+    /// Check every history row has a record to sync, and every record has been built into history
+    Verify {
+        /// Write whatever is missing from either, unless there is more than the configured maximum
+        #[arg(long)]
+        repair: bool,
+    },
+
+    /// List the commands currently running, across all sessions
+    Running {
+        /// Include the session id of each command
+        #[arg(long)]
+        session: bool,
+    },
+}
+
+impl Cmd {
+    /// Only running the daemon opens the databases. The other subcommands just talk to it, and
+    /// `warm` runs on every shell start.
+    pub async fn run(self, settings: Settings) -> Result<()> {
+        match self.subcmd {
+            None | Some(SubCmd::Run) => {
+                let db_path = PathBuf::from(settings.db_path.as_str());
+                let record_store_path = PathBuf::from(settings.record_store_path.as_str());
+
+                let history_db = Sqlite::new(db_path, settings.local_timeout).await?;
+                let store = SqliteStore::new(record_store_path, settings.local_timeout).await?;
+
+                listen(settings, store, history_db).await
+            }
+            Some(SubCmd::Warm) => warm(&settings).await,
+            Some(SubCmd::Status) => status(&settings).await,
+            Some(SubCmd::Restart { graceful, binary }) => {
+                // The daemon may run from another directory, so pass it an absolute path
+                let binary = match binary {
+                    Some(binary) => std::fs::canonicalize(&binary)
+                        .wrap_err_with(|| format!("could not find {}", binary.display()))?,
+                    None => std::env::current_exe()?,
+                };
+
+                if graceful {
+                    restart_in_place(&settings, &binary).await
+                } else {
+                    restart(&settings, &binary).await
+                }
+            }
+            Some(SubCmd::Pause { duration }) => pause(&settings, duration).await,
+            Some(SubCmd::Resume) => {
+                client(&settings).await?.resume().await?;
+                println!("Sync resumed");
+
+                Ok(())
+            }
+            Some(SubCmd::Verify { repair }) => verify(&settings, repair).await,
+            Some(SubCmd::Running { session }) => running(&settings, session).await,
+        }
+    }
+}
+
+async fn client(settings: &Settings) -> Result<HistoryClient> {
+    HistoryClient::new(
+        #[cfg(not(unix))]
+        settings.daemon.tcp_port,
+        #[cfg(unix)]
+        settings.daemon.socket_path.clone(),
+    )
+    .await
+}
+
+fn format_until(timestamp: u64) -> String {
+    let until = i64::try_from(timestamp)
+        .ok()
+        .and_then(|t| OffsetDateTime::from_unix_timestamp(t).ok())
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+
+    let remaining: Duration = (until - OffsetDateTime::now_utc())
+        .try_into()
+        .unwrap_or_default();
+
+    // Only the largest unit is shown, so round to the nearest minute rather than printing 9m
+    // moments after pausing for 10m
+    let remaining = match remaining.as_secs() {
+        secs @ 0..=59 => Duration::from_secs(secs),
+        secs => Duration::from_secs((secs + 30) / 60 * 60),
+    };
+
+    format!("for {}", format_duration(remaining))
+}
+
+async fn status(settings: &Settings) -> Result<()> {
+    let status = client(settings).await?.status().await?;
+
+    println!("Daemon running (pid {})", status.pid);
+
+    if status.sync_disabled {
+        println!("Sync disabled");
+    } else if status.sync_paused_until > 0 {
+        println!("Sync paused {}", format_until(status.sync_paused_until));
+    }
+
+    if status.stores_degraded {
+        println!("History and the record store disagree, see `atuin daemon verify`");
+    }
+
+    if status.db_degraded {
+        println!("Databases unavailable, history is not being saved. Check the daemon's logs");
+    } else if status.db_reconnects > 0 {
+        println!("Databases reopened {} times", status.db_reconnects);
+    }
+
+    Ok(())
+}
+
+async fn pause(settings: &Settings, duration: Option<Duration>) -> Result<()> {
+    // zero asks the daemon for its maximum, so don't let a tiny duration round down to it
+    let seconds = duration.map_or(0, |d| d.as_secs().max(1));
+    let until = client(settings).await?.pause(seconds).await?;
+
+    println!("Sync paused {}", format_until(until));
+
+    Ok(())
+}
+
+/// Poll the daemon until it is (or isn't) reachable
+async fn wait_for(settings: &Settings, reachable: bool) -> Result<()> {
+    for _ in 0..50 {
+        if client(settings).await.is_ok() == reachable {
+            return Ok(());
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    if reachable {
+        bail!("timed out waiting for the daemon to start");
+    }
+
+    bail!("timed out waiting for the daemon to stop");
+}
+
+/// Poll until the process with this pid has exited
+async fn wait_for_exit(pid: u32) -> Result<()> {
+    let pid = sysinfo::Pid::from_u32(pid);
+    let mut system = System::new();
+
+    for _ in 0..50 {
+        if !system.refresh_process(pid) {
+            return Ok(());
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    bail!("timed out waiting for the daemon (pid {pid}) to exit");
+}
+
+/// Have the daemon exec `binary` in place. It keeps listening throughout, so unlike a stop and
+/// start, no shell sees it missing. Works under systemd too, as the pid doesn't change.
+async fn restart_in_place(settings: &Settings, binary: &Path) -> Result<()> {
+    let binary = binary
+        .to_str()
+        .ok_or_eyre("binary path is not valid UTF-8")?;
+
+    let mut daemon = client(settings)
+        .await
+        .wrap_err("the daemon is not running")?;
+    let status = daemon.status().await;
+    let before = match status {
+        Err(e) if e.is::<Unsupported>() => {
+            bail!("the running daemon is too old to restart in place, run `atuin daemon restart` without --graceful")
+        }
+        status => status?,
+    };
+    daemon.restart(Some(binary.to_string())).await?;
+
+    // The daemon waits for this connection to close before it restarts
+    drop(daemon);
+
+    // The pid stays the same, so watch for a new start time instead
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let status = async { client(settings).await?.status().await }.await;
+        let Ok(status) = status else {
+            continue;
+        };
+
+        if status.started_at != before.started_at {
+            println!("Restarted daemon in place (pid {})", status.pid);
+
+            if status.version != atuin_daemon::VERSION {
+                println!(
+                    "The daemon speaks protocol {}, but this atuin expects {}. Is {binary} the same version?",
+                    status.version,
+                    atuin_daemon::VERSION
+                );
+            }
+
+            return Ok(());
+        }
+    }
+
+    bail!("timed out waiting for the daemon to restart, check its logs for why");
+}
+
+/// The pid of the process listening on the daemon socket
+#[cfg(unix)]
+async fn socket_owner(settings: &Settings) -> Option<u32> {
+    let stream = tokio::net::UnixStream::connect(&settings.daemon.socket_path)
+        .await
+        .ok()?;
+    let pid = stream.peer_cred().ok()?.pid()?;
+
+    u32::try_from(pid).ok()
+}
+
+/// Daemons from before the Status and Shutdown RPCs can't be asked to stop, so signal them like
+/// `kill` would. Returns the pid stopped.
+async fn stop_old_daemon(settings: &Settings) -> Result<u32> {
+    #[cfg(unix)]
+    if let Some(pid) = socket_owner(settings).await {
+        use rustix::process::{kill_process, Pid, Signal};
+
+        println!(
+            "The running daemon (pid {pid}) is too old to stop on request, sending it SIGTERM"
+        );
+        println!("Commands it was tracking won't be handed over, and private mode won't be kept");
+
+        let target = i32::try_from(pid)
+            .ok()
+            .and_then(Pid::from_raw)
+            .ok_or_eyre("invalid daemon pid")?;
+        kill_process(target, Signal::Term)?;
+
+        return Ok(pid);
+    }
+
+    #[cfg(not(unix))]
+    let _ = settings;
+
+    bail!("the running daemon is too old to stop on request. Stop it yourself, eg with `kill` or `systemctl --user stop atuin-daemon`, then run this again");
+}
+
+async fn restart(settings: &Settings, binary: &Path) -> Result<()> {
+    if settings.daemon.systemd_socket {
+        bail!("the daemon is managed by systemd, restart it with systemctl or --graceful instead");
+    }
+
+    if let Ok(mut client) = client(settings).await {
+        let status = client.status().await;
+        let pid = match status {
+            Ok(status) => {
+                client.shutdown(true).await?;
+                status.pid
+            }
+            Err(e) if e.is::<Unsupported>() => {
+                drop(client);
+                stop_old_daemon(settings).await?
+            }
+            Err(e) => return Err(e),
+        };
+
+        wait_for(settings, false).await?;
+        wait_for_exit(pid).await?;
+        println!("Stopped daemon (pid {pid})");
+    } else {
+        println!("Daemon is not running");
+    }
+
+    // Keep the daemon's output, otherwise there's no way to tell why it failed to start
+    let log_path = atuin_common::utils::data_dir().join(DAEMON_LOG_FILENAME);
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)?;
+
+    // Run the daemon detached from this terminal. It inherits our environment, so it picks up
+    // the same config and socket path.
+    let mut daemon = Command::new(binary);
+    daemon
+        .arg("daemon")
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        daemon.process_group(0);
+    }
+
+    let mut child = daemon.spawn()?;
+
+    if let Err(e) = wait_for(settings, true).await {
+        if let Some(status) = child.try_wait()? {
+            bail!(
+                "the daemon exited with {status}, see {} for details",
+                log_path.display()
+            );
+        }
+
+        return Err(e.wrap_err(format!("see {} for details", log_path.display())));
+    }
+    let pid = client(settings).await?.status().await?.pid;
+    println!("Started daemon (pid {pid})");
+
+    Ok(())
+}
+
+async fn verify(settings: &Settings, repair: bool) -> Result<()> {
+    let report = client(settings).await?.verify_stores(repair).await?;
+
+    println!(
+        "{} history rows, {} history records",
+        report.history_rows, report.store_records
+    );
+
+    if report.missing_from_store == 0 && report.missing_from_history == 0 {
+        println!("History and the record store agree");
+        return Ok(());
+    }
+
+    println!(
+        "{} history rows have no record, so will never sync",
+        report.missing_from_store
+    );
+    println!(
+        "{} records were never added to history, so don't show up",
+        report.missing_from_history
+    );
+
+    if report.repaired {
+        println!("Repaired");
+    } else if report.degraded {
+        println!("Too many to repair automatically. Run `atuin history init-store` to add the missing records, and `atuin store rebuild history` to add the missing history");
+    } else {
+        println!("Run again with --repair to fix");
+    }
+
+    Ok(())
+}
+
+async fn running(settings: &Settings, session: bool) -> Result<()> {
+    let running = client(settings).await?.running(session).await?;
+
+    for h in running {
+        let elapsed = format_duration(Duration::from_nanos(h.elapsed));
+
+        if session {
+            println!("{elapsed}\t{}\t{}\t{}", h.session, h.cwd, h.command);
+        } else {
+            println!("{elapsed}\t{}\t{}", h.cwd, h.command);
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the daemon is answering, so the first `history start` doesn't need to find out the hard
+/// way that it's missing. Never fails, the result is only recorded.
+async fn warm(settings: &Settings) -> Result<()> {
+    let status = async { client(settings).await?.status().await }.await;
+
+    let (state, version) = match status.ok() {
+        Some(status) => (DaemonState::Up, Some(status.version)),
+        None => (DaemonState::Down, None),
+    };
 
-pub async fn run(settings: Settings, store: SqliteStore, history_db: Sqlite) -> Result<()> {
-    listen(settings, store, history_db).await?;
+    if let Err(e) = warm::record(state, version) {
+        tracing::debug!("failed to record daemon state: {e}");
+    }
 
     Ok(())
 }
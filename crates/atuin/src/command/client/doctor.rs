@@ -255,6 +255,44 @@ impl SyncInfo {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct DaemonInfo {
+    pub enabled: bool,
+
+    /// Permission bits of the daemon socket, in octal. None if the socket doesn't exist, or on
+    /// systems without unix sockets
+    pub socket_mode: Option<String>,
+}
+
+impl DaemonInfo {
+    pub fn new(settings: &Settings) -> Self {
+        #[cfg(unix)]
+        let socket_mode = {
+            use std::os::unix::fs::PermissionsExt;
+
+            std::fs::metadata(&settings.daemon.socket_path)
+                .ok()
+                .map(|m| format!("{:04o}", m.permissions().mode() & 0o7777))
+        };
+
+        #[cfg(not(unix))]
+        let socket_mode = None;
+
+        Self {
+            enabled: settings.daemon.enabled,
+            socket_mode,
+        }
+    }
+
+    /// Whether anyone other than the owner can connect to the socket
+    pub fn socket_shared(&self) -> bool {
+        self.socket_mode
+            .as_deref()
+            .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+            .is_some_and(|mode| mode & 0o077 != 0)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct AtuinInfo {
     pub version: String,
@@ -263,6 +301,8 @@ struct AtuinInfo {
     /// I'm just calling it Atuin Cloud for lack of a better name atm
     pub sync: Option<SyncInfo>,
 
+    pub daemon: DaemonInfo,
+
     pub sqlite_version: String,
 }
 
@@ -288,6 +328,7 @@ impl AtuinInfo {
         Self {
             version: crate::VERSION.to_string(),
             sync,
+            daemon: DaemonInfo::new(settings),
             sqlite_version,
         }
     }
@@ -316,6 +357,7 @@ fn checks(info: &DoctorDump) {
     let zfs_error = "[Filesystem] ZFS is known to have some issues with SQLite. Atuin uses SQLite heavily. If you are having poor performance, there are some workarounds here: https://github.com/atuinsh/atuin/issues/952".bold().red();
     let bash_plugin_error = "[Shell] If you are using Bash, Atuin requires that either bash-preexec or ble.sh be installed. An older ble.sh may not be detected. so ignore this if you have it set up! Read more here: https://docs.atuin.sh/guide/installation/#bash".bold().red();
     let blesh_loading_order_error = "[Shell] Atuin seems to be loaded before ble.sh is sourced. In .bashrc, make sure to initialize Atuin after sourcing ble.sh.".bold().red();
+    let daemon_socket_error = "[Daemon] The daemon socket is accessible to other users, who could read your history or write to it. Unless this is intentional, set daemon.socket_mode = 0o600 and restart the daemon.".bold().red();
 
     // ZFS: https://github.com/atuinsh/atuin/issues/952
     if info.system.disks.iter().any(|d| d.filesystem == "zfs") {
@@ -340,6 +382,11 @@ fn checks(info: &DoctorDump) {
             println!("{blesh_loading_order_error}");
         }
     }
+
+    // Daemon
+    if info.atuin.daemon.socket_shared() {
+        println!("{daemon_socket_error}");
+    }
 }
 
 pub async fn run(settings: &Settings) -> Result<()> {
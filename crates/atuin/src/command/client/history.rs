@@ -323,6 +323,19 @@ impl Cmd {
         Ok(())
     }
 
+    /// Connect to the daemon, unless it was seen to be unreachable moments ago
+    async fn daemon_client(settings: &Settings) -> Result<atuin_daemon::client::HistoryClient> {
+        atuin_daemon::warm::connect(|| {
+            atuin_daemon::client::HistoryClient::new(
+                #[cfg(not(unix))]
+                settings.daemon.tcp_port,
+                #[cfg(unix)]
+                settings.daemon.socket_path.clone(),
+            )
+        })
+        .await
+    }
+
     async fn handle_daemon_start(settings: &Settings, command: &[String]) -> Result<()> {
         let command = command.join(" ");
 
@@ -341,15 +354,10 @@ impl Cmd {
             return Ok(());
         }
 
-        let resp = atuin_daemon::client::HistoryClient::new(
-            #[cfg(not(unix))]
-            settings.daemon.tcp_port,
-            #[cfg(unix)]
-            settings.daemon.socket_path.clone(),
-        )
-        .await?
-        .start_history(h)
-        .await?;
+        let resp = Self::daemon_client(settings)
+            .await?
+            .start_history(h)
+            .await?;
 
         // print the ID
         // we use this as the key for calling end
@@ -432,15 +440,10 @@ impl Cmd {
         exit: i64,
         duration: Option<u64>,
     ) -> Result<()> {
-        let resp = atuin_daemon::client::HistoryClient::new(
-            #[cfg(not(unix))]
-            settings.daemon.tcp_port,
-            #[cfg(unix)]
-            settings.daemon.socket_path.clone(),
-        )
-        .await?
-        .end_history(id.to_string(), duration.unwrap_or(0), exit)
-        .await?;
+        let resp = Self::daemon_client(settings)
+            .await?
+            .end_history(id.to_string(), duration.unwrap_or(0), exit)
+            .await?;
 
         Ok(())
     }
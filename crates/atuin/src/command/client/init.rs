@@ -158,6 +158,18 @@ $env.config = (
         Ok(())
     }
 
+    /// Connect to the daemon in the background as the shell starts, so the first command's
+    /// `history start` doesn't pay for it
+    fn daemon_warm(&self) {
+        let warm = match self.shell {
+            Shell::Zsh | Shell::Bash => "( atuin daemon warm & ) >/dev/null 2>&1",
+            Shell::Fish => "atuin daemon warm >/dev/null 2>&1 &; disown",
+            Shell::Nu | Shell::Xonsh => return,
+        };
+
+        println!("{warm}");
+    }
+
     pub async fn run(self, settings: &Settings) -> Result<()> {
         if settings.dotfiles.enabled {
             self.dotfiles_init(settings).await?;
@@ -165,6 +177,10 @@ $env.config = (
             self.static_init();
         }
 
+        if cfg!(feature = "daemon") && settings.daemon.enabled {
+            self.daemon_warm();
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,51 @@
+use clap::Subcommand;
+use eyre::{bail, Result};
+
+use atuin_client::settings::Settings;
+use atuin_daemon::client::HistoryClient;
+
+#[derive(Subcommand, Debug)]
+#[command(infer_subcommands = true)]
+pub enum Cmd {
+    /// Stop recording history until private mode is turned off, or the daemon is stopped. It is
+    /// kept across `atuin daemon restart`
+    On,
+
+    /// Resume recording history
+    Off,
+
+    /// Print whether private mode is on
+    Status,
+}
+
+impl Cmd {
+    pub async fn run(self, settings: &Settings) -> Result<()> {
+        if !settings.daemon.enabled {
+            bail!("private mode requires the daemon. Enable it with daemon.enabled = true");
+        }
+
+        let enabled = match self {
+            Self::On => Some(true),
+            Self::Off => Some(false),
+            Self::Status => None,
+        };
+
+        let enabled = HistoryClient::new(
+            #[cfg(not(unix))]
+            settings.daemon.tcp_port,
+            #[cfg(unix)]
+            settings.daemon.socket_path.clone(),
+        )
+        .await?
+        .private_mode(enabled)
+        .await?;
+
+        if enabled {
+            println!("Private mode is on, history is not being recorded");
+        } else {
+            println!("Private mode is off, history is being recorded");
+        }
+
+        Ok(())
+    }
+}
@@ -22,6 +22,8 @@ mod history_list;
 mod inspector;
 mod interactive;
 
+#[cfg(feature = "daemon")]
+pub use duration::format_duration;
 pub use duration::format_duration_into;
 
 #[allow(clippy::struct_excessive_bools, clippy::struct_field_names)]
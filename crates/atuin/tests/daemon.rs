@@ -0,0 +1,199 @@
+#![cfg(unix)]
+
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::time::Duration;
+
+use atuin_client::database::{Database, Sqlite};
+use atuin_client::history::History;
+use atuin_common::utils::uuid_v7;
+use atuin_daemon::client::HistoryClient;
+use time::OffsetDateTime;
+
+struct Daemon {
+    dir: PathBuf,
+    child: Child,
+}
+
+impl Daemon {
+    fn start() -> Self {
+        let dir = std::env::temp_dir().join(format!("atuin-daemon-{}", uuid_v7().as_simple()));
+        std::fs::create_dir_all(dir.join("config")).unwrap();
+
+        let config = format!(
+            r#"
+db_path = "{dir}/history.db"
+record_store_path = "{dir}/records.db"
+key_path = "{dir}/key"
+
+[daemon]
+enabled = true
+socket_path = "{dir}/atuin.sock"
+components = {{ sync = false }}
+"#,
+            dir = dir.display()
+        );
+        std::fs::write(dir.join("config/config.toml"), config).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_atuin"))
+            .arg("daemon")
+            .env("ATUIN_CONFIG_DIR", dir.join("config"))
+            .env("XDG_DATA_HOME", dir.join("data"))
+            .spawn()
+            .unwrap();
+
+        Self { dir, child }
+    }
+
+    fn socket(&self) -> String {
+        self.dir.join("atuin.sock").to_string_lossy().into_owned()
+    }
+
+    async fn connect(&self) -> HistoryClient {
+        for _ in 0..100 {
+            if Path::new(&self.socket()).exists() {
+                if let Ok(client) = HistoryClient::new(self.socket()).await {
+                    return client;
+                }
+            }
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        panic!("daemon did not start");
+    }
+}
+
+impl Daemon {
+    /// Wait for the daemon to exit. This must not block the runtime, as the daemon waits for our
+    /// connections to close before exiting.
+    async fn exited(mut self) -> ExitStatus {
+        for _ in 0..100 {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status;
+            }
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        panic!("daemon did not exit");
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn command(command: &str) -> History {
+    History::daemon()
+        .timestamp(OffsetDateTime::now_utc())
+        .command(command)
+        .cwd("/")
+        .session(uuid_v7().as_simple().to_string())
+        .hostname("host:user")
+        .build()
+        .into()
+}
+
+#[tokio::test]
+async fn restart_in_place_keeps_connections_and_running_commands() {
+    let daemon = Daemon::start();
+    let mut client = daemon.connect().await;
+
+    let before = client.status().await.unwrap();
+
+    let id = client.start_history(command("sleep 100")).await.unwrap();
+
+    client
+        .restart(Some(env!("CARGO_BIN_EXE_atuin").to_string()))
+        .await
+        .unwrap();
+
+    // The socket is never missing, so connecting works straight away, and the connection waits
+    // for the new binary to answer
+    let mut fresh = HistoryClient::new(daemon.socket()).await.unwrap();
+    let after = fresh.status().await.unwrap();
+
+    assert_eq!(after.pid, before.pid);
+    assert_ne!(after.started_at, before.started_at);
+
+    // The connection held across the restart was closed, but reconnects on the next request
+    let after = match client.status().await {
+        Ok(status) => status,
+        Err(_) => client.status().await.unwrap(),
+    };
+    assert_eq!(after.pid, before.pid);
+
+    // Without the running commands handed over, this fails with "not found"
+    client.end_history(id, 1, 0).await.unwrap();
+
+    client.shutdown(false).await.unwrap();
+    assert!(daemon.exited().await.success());
+}
+
+#[tokio::test]
+async fn replaced_history_db_is_reopened() {
+    let daemon = Daemon::start();
+    let mut client = daemon.connect().await;
+
+    let id = client.start_history(command("echo before")).await.unwrap();
+    client.end_history(id, 1, 0).await.unwrap();
+
+    // eg a restore from backup, or a network mount coming back
+    for file in ["history.db", "history.db-wal", "history.db-shm"] {
+        let _ = std::fs::remove_file(daemon.dir.join(file));
+    }
+
+    let id = client.start_history(command("echo after")).await.unwrap();
+    client.end_history(id.clone(), 1, 0).await.unwrap();
+
+    assert_eq!(client.status().await.unwrap().db_reconnects, 1);
+
+    // Saved to the new file, not the deleted one
+    let db = Sqlite::new(daemon.dir.join("history.db"), 5.0)
+        .await
+        .unwrap();
+    let saved = db.load(&id).await.unwrap().expect("history was not saved");
+    assert_eq!(saved.command, "echo after");
+}
+
+#[tokio::test]
+async fn drift_between_history_and_store_is_repaired() {
+    let daemon = Daemon::start();
+    let mut client = daemon.connect().await;
+    let db = Sqlite::new(daemon.dir.join("history.db"), 5.0)
+        .await
+        .unwrap();
+
+    let id = client.start_history(command("echo synced")).await.unwrap();
+    client.end_history(id.clone(), 1, 0).await.unwrap();
+
+    let report = client.verify_stores(false).await.unwrap();
+    assert_eq!(report.missing_from_store, 0);
+    assert_eq!(report.missing_from_history, 0);
+
+    // A row written without its record, so it would never sync
+    db.save(&command("echo unsynced")).await.unwrap();
+
+    // A record never built into history, so it would never show
+    let h = db.load(&id).await.unwrap().unwrap();
+    db.delete_rows(&[h.id.clone()]).await.unwrap();
+
+    let report = client.verify_stores(false).await.unwrap();
+    assert_eq!(report.missing_from_store, 1);
+    assert_eq!(report.missing_from_history, 1);
+    assert!(!report.repaired);
+
+    let report = client.verify_stores(true).await.unwrap();
+    assert!(report.repaired);
+    assert!(!report.degraded);
+
+    let report = client.verify_stores(false).await.unwrap();
+    assert_eq!(report.missing_from_store, 0);
+    assert_eq!(report.missing_from_history, 0);
+    assert!(db.load(&id).await.unwrap().is_some());
+}